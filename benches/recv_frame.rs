@@ -0,0 +1,161 @@
+//! Compares allocations and copies of `recv_frame` against the previous receive path.
+//!
+//! The previous implementation read the body into `vec![0u8; len]`, copied header and body into
+//! a fresh `BytesMut`, and let `nwd1::decode` copy the payload once more. `recv_frame` now reads
+//! quinn chunks and slices the payload out of them.
+//!
+//! Run with `cargo bench --bench recv_frame`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use netid64::NetId64;
+use nwd1::{Frame, decode, encode};
+use nwd1_quic::recv_frame;
+use quinn::rustls::RootCertStore;
+use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use quinn::{ClientConfig, Connection, Endpoint, RecvStream, ServerConfig};
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+static ALLOC_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(layout.size(), Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        ALLOC_BYTES.fetch_add(new_size, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const FRAMES: usize = 256;
+
+/// The receive path as it was before chunked reads: two full copies of every payload.
+async fn recv_frame_legacy(stream: &mut RecvStream) -> Option<Frame> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header).await.ok()?;
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await.ok()?;
+
+    let mut buf = BytesMut::with_capacity(8 + len);
+    buf.extend_from_slice(&header);
+    buf.extend_from_slice(&body);
+    decode(&buf.freeze()).ok()
+}
+
+struct Report {
+    allocs: usize,
+    bytes: usize,
+    elapsed: Duration,
+}
+
+async fn connect() -> (Connection, Connection, Endpoint, Endpoint) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_der = CertificateDer::from(cert.cert);
+    let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+    let server_config = ServerConfig::with_single_cert(vec![cert_der.clone()], key.into()).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(cert_der).unwrap();
+    let client_config = ClientConfig::with_root_certificates(Arc::new(roots)).unwrap();
+
+    let addr: SocketAddr = (Ipv4Addr::LOCALHOST, 0).into();
+    let server_ep = Endpoint::server(server_config, addr).unwrap();
+    let mut client_ep = Endpoint::client(addr).unwrap();
+    client_ep.set_default_client_config(client_config);
+
+    let connecting = client_ep.connect(server_ep.local_addr().unwrap(), "localhost").unwrap();
+    let accepting = async { server_ep.accept().await.unwrap().await };
+    let (client, server) = tokio::join!(connecting, accepting);
+    (client.unwrap(), server.unwrap(), client_ep, server_ep)
+}
+
+async fn run(payload_len: usize, legacy: bool) -> Report {
+    let (client, server, _client_ep, _server_ep) = connect().await;
+
+    // Pre-encode once so the sender adds no per-frame allocations of its own
+    let data = encode(&Frame {
+        id: NetId64::make(1, 7, 42),
+        kind: 1,
+        ver: 1,
+        payload: Bytes::from(vec![0x5A; payload_len]),
+    });
+
+    let mut send = client.open_uni().await.unwrap();
+    let writer = tokio::spawn(async move {
+        for _ in 0..FRAMES {
+            send.write_chunk(data.clone()).await.unwrap();
+        }
+        send.finish().unwrap();
+        send.stopped().await.ok();
+    });
+
+    let mut recv = server.accept_uni().await.unwrap();
+    let (allocs, bytes) = (ALLOCS.load(Ordering::Relaxed), ALLOC_BYTES.load(Ordering::Relaxed));
+    let start = Instant::now();
+    let mut received = 0;
+    loop {
+        let frame = if legacy {
+            recv_frame_legacy(&mut recv).await
+        } else {
+            recv_frame(&mut recv).await.unwrap()
+        };
+        match frame {
+            Some(frame) => {
+                assert_eq!(frame.payload.len(), payload_len);
+                received += 1;
+            }
+            None => break,
+        }
+    }
+    let elapsed = start.elapsed();
+    let report = Report {
+        allocs: ALLOCS.load(Ordering::Relaxed) - allocs,
+        bytes: ALLOC_BYTES.load(Ordering::Relaxed) - bytes,
+        elapsed,
+    };
+    assert_eq!(received, FRAMES);
+    writer.await.unwrap();
+    report
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    println!(
+        "{:>10} {:>8} {:>12} {:>16} {:>12}",
+        "payload", "path", "allocs/frame", "alloc bytes/frame", "MiB/s"
+    );
+    for payload_len in [256, 16 * 1024, 256 * 1024, 2 * 1024 * 1024] {
+        for (name, legacy) in [("legacy", true), ("chunked", false)] {
+            let r = run(payload_len, legacy).await;
+            let mib = (payload_len * FRAMES) as f64 / (1024.0 * 1024.0);
+            println!(
+                "{:>10} {:>8} {:>12.1} {:>16.0} {:>12.1}",
+                payload_len,
+                name,
+                r.allocs as f64 / FRAMES as f64,
+                r.bytes as f64 / FRAMES as f64,
+                mib / r.elapsed.as_secs_f64()
+            );
+        }
+    }
+}
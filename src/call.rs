@@ -0,0 +1,420 @@
+//! Streaming calls on a dedicated bidirectional stream, delimited by terminal frames.
+//!
+//! `KIND` values [`KIND_END`], [`KIND_ERROR`] and [`KIND_CANCEL`] are reserved for terminal
+//! frames carrying the call's `ID`:
+//!
+//! - `END` closes one direction of a call after its last data frame;
+//! - `ERROR` aborts the call from the responding side, with a UTF-8 message as payload;
+//! - `CANCEL` aborts the call from the calling side.
+//!
+//! Each side finishes its send half after its terminal frame, so both ends know exactly when
+//! the call is over.
+
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::Bytes;
+use futures::Stream;
+use netid64::NetId64;
+use nwd1::Frame;
+use quinn::{Connection, RecvStream, SendStream, WriteError};
+
+use crate::interceptor::clone_frame;
+use crate::{FrameReader, InterceptorStack, Nwd1QuicError, propagate, send_frame};
+
+/// Terminal frame closing one direction of a call.
+pub const KIND_END: u8 = 0xFD;
+/// Terminal frame aborting a call from the responding side; the payload is a UTF-8 message.
+pub const KIND_ERROR: u8 = 0xFE;
+/// Terminal frame aborting a call from the calling side.
+pub const KIND_CANCEL: u8 = 0xFF;
+
+/// Whether `kind` is reserved for terminal frames.
+pub fn is_terminal_kind(kind: u8) -> bool {
+    kind >= KIND_END
+}
+
+/// A terminal frame of `kind` for the call `id`.
+pub(crate) fn terminal_frame(id: NetId64, kind: u8, payload: Bytes) -> Frame {
+    Frame { id, kind, ver: 0, payload }
+}
+
+/// Open a server-streaming call: send `request` on a new stream and receive the responses.
+///
+/// Dropping the returned receiver before the responses ended cancels the call.
+pub async fn call_streaming(
+    conn: &Connection,
+    request: &Frame,
+) -> Result<CallReceiver, Nwd1QuicError> {
+    call_streaming_with(conn, request, &InterceptorStack::new()).await
+}
+
+/// Open a server-streaming call like [`call_streaming`], running every frame of the call
+/// through `interceptors`.
+pub async fn call_streaming_with(
+    conn: &Connection,
+    request: &Frame,
+    interceptors: &InterceptorStack,
+) -> Result<CallReceiver, Nwd1QuicError> {
+    let (send, recv) = conn.open_bi().await?;
+    let mut sender = CallSender::new(send, request.id);
+    sender.set_interceptors(interceptors.clone());
+    sender.send(request).await?;
+    Ok(CallReceiver::new(recv, interceptors, Some(sender)))
+}
+
+/// Open a full-duplex call identified by `id` on a new stream.
+///
+/// Every frame sent on it carries `id`. The stream reaches the peer with the first frame sent.
+pub async fn call_duplex(
+    conn: &Connection,
+    id: NetId64,
+) -> Result<(CallSender, CallReceiver), Nwd1QuicError> {
+    call_duplex_with(conn, id, &InterceptorStack::new()).await
+}
+
+/// Open a full-duplex call like [`call_duplex`], running every frame of the call through
+/// `interceptors`.
+pub async fn call_duplex_with(
+    conn: &Connection,
+    id: NetId64,
+    interceptors: &InterceptorStack,
+) -> Result<(CallSender, CallReceiver), Nwd1QuicError> {
+    let (send, recv) = conn.open_bi().await?;
+    let mut sender = CallSender::new(send, id);
+    sender.set_interceptors(interceptors.clone());
+    Ok((sender, CallReceiver::new(recv, interceptors, None)))
+}
+
+/// The sending half of a call.
+///
+/// Dropping it without [`end`](Self::end), [`fail`](Self::fail) or [`cancel`](Self::cancel)
+/// sends `CANCEL` in the background.
+#[derive(Debug)]
+pub struct CallSender {
+    stream: Option<SendStream>,
+    id: NetId64,
+    interceptors: InterceptorStack,
+}
+
+impl CallSender {
+    /// Send the frames of call `id` on `stream`.
+    pub fn new(stream: SendStream, id: NetId64) -> Self {
+        Self { stream: Some(stream), id, interceptors: InterceptorStack::new() }
+    }
+
+    /// Run every frame sent from now on, terminal frames included, through `interceptors`.
+    pub fn set_interceptors(&mut self, interceptors: InterceptorStack) {
+        self.interceptors = interceptors;
+    }
+
+    /// The call's `ID`, carried by all of its frames.
+    pub fn id(&self) -> NetId64 {
+        self.id
+    }
+
+    /// Send a data frame, whose `KIND` must not be reserved; a reserved one fails with
+    /// [`Nwd1QuicError::ReservedKind`].
+    ///
+    /// The frame is sent with the call's `ID` in place of its own, so the call's `CANCEL`
+    /// reaches the peer's handling of every data frame. A [deadline](crate::with_deadline) or
+    /// [trace context](crate::with_trace_context) in scope is carried by the frame.
+    pub async fn send(&mut self, frame: &Frame) -> Result<(), Nwd1QuicError> {
+        if is_terminal_kind(frame.kind) {
+            return Err(Nwd1QuicError::ReservedKind { kind: frame.kind });
+        }
+        let stream = self.stream.as_mut().ok_or(Nwd1QuicError::Closed)?;
+        let frame = Frame { id: self.id, ..clone_frame(frame) };
+        let Some(frame) = self.interceptors.on_send(frame)? else { return Ok(()) };
+        let frame = propagate(&frame)?.unwrap_or(frame);
+        send_frame(stream, &frame).await
+    }
+
+    /// Send `END` after the last data frame and finish the stream.
+    pub async fn end(mut self) -> Result<(), Nwd1QuicError> {
+        self.terminate(KIND_END, Bytes::new()).await
+    }
+
+    /// Abort the call with `message`, sending `ERROR`.
+    pub async fn fail(mut self, message: &str) -> Result<(), Nwd1QuicError> {
+        self.terminate(KIND_ERROR, Bytes::copy_from_slice(message.as_bytes())).await
+    }
+
+    /// Abort the call, sending `CANCEL`.
+    pub async fn cancel(mut self) -> Result<(), Nwd1QuicError> {
+        self.terminate(KIND_CANCEL, Bytes::new()).await
+    }
+
+    /// Finish the stream without a terminal frame, once the peer has ended the call.
+    fn close(&mut self) {
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.finish();
+        }
+    }
+
+    async fn terminate(&mut self, kind: u8, payload: Bytes) -> Result<(), Nwd1QuicError> {
+        let mut stream = self.stream.take().ok_or(Nwd1QuicError::Closed)?;
+        if let Some(frame) = self.interceptors.on_send(terminal_frame(self.id, kind, payload))? {
+            send_frame(&mut stream, &frame).await?;
+        }
+        stream.finish().map_err(|e| WriteError::from(e).into())
+    }
+}
+
+impl Drop for CallSender {
+    fn drop(&mut self) {
+        let Some(mut stream) = self.stream.take() else { return };
+        let cancel = terminal_frame(self.id, KIND_CANCEL, Bytes::new());
+        let Ok(Some(cancel)) = self.interceptors.on_send(cancel) else {
+            let _ = stream.finish();
+            return;
+        };
+        // Without a runtime the stream is dropped, which resets it instead
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                if send_frame(&mut stream, &cancel).await.is_ok() {
+                    let _ = stream.finish();
+                }
+            });
+        }
+    }
+}
+
+/// The receiving half of a call: a [`Stream`] of data frames.
+///
+/// Ends after `END`. `ERROR` is yielded as [`Nwd1QuicError::Remote`], `CANCEL` as
+/// [`Nwd1QuicError::Cancelled`], and a stream finished without a terminal frame as
+/// [`Nwd1QuicError::Closed`]; nothing follows any of them.
+#[derive(Debug)]
+pub struct CallReceiver {
+    reader: FrameReader,
+    interceptors: InterceptorStack,
+    done: bool,
+    /// The request side of a server-streaming call, cancelled if dropped early.
+    sender: Option<CallSender>,
+}
+
+impl CallReceiver {
+    fn new(
+        stream: RecvStream,
+        interceptors: &InterceptorStack,
+        sender: Option<CallSender>,
+    ) -> Self {
+        Self {
+            reader: FrameReader::new(stream),
+            interceptors: interceptors.clone(),
+            done: false,
+            sender,
+        }
+    }
+
+    /// Receive the next data frame, or `None` after `END`.
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>, Nwd1QuicError> {
+        std::future::poll_fn(|cx| self.poll_next_frame(cx)).await
+    }
+
+    /// Poll for the next data frame.
+    ///
+    /// Frames dropped by an interceptor are skipped; a rejected frame is returned as the
+    /// interceptor's error, and receiving can continue after it.
+    pub fn poll_next_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Frame>, Nwd1QuicError>> {
+        if self.done {
+            return Poll::Ready(Ok(None));
+        }
+        let result = loop {
+            let frame = match ready!(self.reader.poll_next_frame(cx)) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break Err(Nwd1QuicError::Closed),
+                Err(e) => break Err(e),
+            };
+            let Some(frame) = self.interceptors.on_recv(frame)? else { continue };
+            if !is_terminal_kind(frame.kind) {
+                return Poll::Ready(Ok(Some(frame)));
+            }
+            break match frame.kind {
+                KIND_END => Ok(None),
+                KIND_ERROR => Err(Nwd1QuicError::Remote {
+                    message: String::from_utf8_lossy(&frame.payload).into_owned(),
+                }),
+                _ => Err(Nwd1QuicError::Cancelled),
+            };
+        };
+        self.done = true;
+        if let Some(sender) = &mut self.sender {
+            sender.close();
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl Stream for CallReceiver {
+    type Item = Result<Frame, Nwd1QuicError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.done {
+            return Poll::Ready(None);
+        }
+        this.poll_next_frame(cx).map(Result::transpose)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+
+    use super::*;
+    use crate::interceptor::clone_frame;
+    use crate::test_util::{frame, start_server};
+    use crate::{Intercept, Interceptor, ReplySender, ServerLimits};
+
+    /// Kind 1 streams `counter` responses, kind 2 fails after one, data frames of a duplex
+    /// call are echoed until its `END`.
+    async fn handler(frame: Frame, reply: ReplySender) -> Result<(), Nwd1QuicError> {
+        match frame.kind {
+            1 => {
+                for n in 0..frame.id.counter() {
+                    reply.send(&Frame { kind: 10, ver: n, ..clone_frame(&frame) }).await?;
+                }
+                reply.end(frame.id).await
+            }
+            2 => {
+                reply.send(&frame).await?;
+                reply.fail(frame.id, "boom").await
+            }
+            KIND_END => reply.end(frame.id).await,
+            _ => reply.send(&frame).await,
+        }
+    }
+
+    #[tokio::test]
+    async fn server_streaming_ends_and_fails() {
+        let running = start_server(ServerLimits::default(), handler).await;
+
+        let responses: Vec<Frame> = call_streaming(&running.conn, &frame(3, 1, "x"))
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(responses.iter().map(|f| f.ver).collect::<Vec<_>>(), [0, 1, 2]);
+
+        let mut call = call_streaming(&running.conn, &frame(1, 2, "x")).await.unwrap();
+        assert!(call.next_frame().await.unwrap().is_some());
+        let Err(err) = call.next_frame().await else { panic!("error frame ignored") };
+        assert!(matches!(err, Nwd1QuicError::Remote { message } if message == "boom"));
+        assert!(call.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn duplex_call_echoes_until_end() {
+        let running = start_server(ServerLimits::default(), handler).await;
+
+        let (mut tx, rx) = call_duplex(&running.conn, NetId64::make(1, 7, 99)).await.unwrap();
+        for ver in 0..3 {
+            tx.send(&Frame { ver, ..frame(ver, 5, "x") }).await.unwrap();
+        }
+        let Err(err) = tx.send(&frame(3, KIND_END, "x")).await else {
+            panic!("reserved kind sent")
+        };
+        assert!(matches!(err, Nwd1QuicError::ReservedKind { kind: KIND_END }));
+        tx.end().await.unwrap();
+
+        // Data frames carry the call's `ID`
+        let echoed: Vec<Frame> = rx.try_collect().await.unwrap();
+        assert!(echoed.iter().all(|f| f.id.counter() == 99));
+        assert_eq!(echoed.iter().map(|f| f.ver).collect::<Vec<_>>(), [0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn intercepts_call_frames() {
+        /// Appends `!` to sent payloads and drops received frames of `KIND` 7.
+        struct Mark;
+
+        impl Interceptor for Mark {
+            fn on_send(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+                frame.payload = [&frame.payload[..], b"!"].concat().into();
+                Ok(Intercept::Pass)
+            }
+
+            fn on_recv(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+                Ok(if frame.kind == 7 { Intercept::Drop } else { Intercept::Pass })
+            }
+        }
+
+        let running = start_server(ServerLimits::default(), handler).await;
+        let interceptors = InterceptorStack::new().with(Mark);
+
+        let id = NetId64::make(1, 7, 99);
+        let (mut tx, rx) = call_duplex_with(&running.conn, id, &interceptors).await.unwrap();
+        for kind in [5, 7, 5] {
+            tx.send(&frame(0, kind, "x")).await.unwrap();
+        }
+        tx.end().await.unwrap();
+        let echoed: Vec<Frame> = rx.try_collect().await.unwrap();
+        let payloads: Vec<&[u8]> = echoed.iter().map(|f| &f.payload[..]).collect();
+        assert_eq!(payloads, [b"x!", b"x!"]);
+
+        let call =
+            call_streaming_with(&running.conn, &frame(2, 1, "x"), &interceptors).await.unwrap();
+        let responses: Vec<Frame> = call.try_collect().await.unwrap();
+        assert_eq!(responses.len(), 2);
+        assert!(responses.iter().all(|f| &f.payload[..] == b"x!"));
+    }
+
+    #[tokio::test]
+    async fn dropping_receiver_cancels_handler() {
+        let (cancelled_tx, mut cancelled) = tokio::sync::mpsc::unbounded_channel();
+        let handler = move |frame: Frame, reply: ReplySender| {
+            let cancelled_tx = cancelled_tx.clone();
+            async move {
+                reply.send(&frame).await?;
+                reply.cancelled().await;
+                cancelled_tx.send(frame.id.counter()).unwrap();
+                Err(Nwd1QuicError::Cancelled)
+            }
+        };
+        let running = start_server(ServerLimits::default(), handler).await;
+
+        let mut call = call_streaming(&running.conn, &frame(4, 1, "x")).await.unwrap();
+        assert!(call.next_frame().await.unwrap().is_some());
+        drop(call);
+        assert_eq!(cancelled.recv().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn cancelling_duplex_call_cancels_handlers() {
+        let (cancelled_tx, mut cancelled) = tokio::sync::mpsc::unbounded_channel();
+        let handler = move |frame: Frame, reply: ReplySender| {
+            let cancelled_tx = cancelled_tx.clone();
+            async move {
+                reply.send(&frame).await?;
+                reply.cancelled().await;
+                cancelled_tx.send(frame.ver).unwrap();
+                Err(Nwd1QuicError::Cancelled)
+            }
+        };
+        let limits = ServerLimits { max_in_flight_per_stream: 2, ..ServerLimits::default() };
+        let running = start_server(limits, handler).await;
+
+        // Cancelled explicitly, and by dropping the sender
+        for ver in [1, 2] {
+            let id = NetId64::make(1, 7, 99);
+            let (mut tx, mut rx) = call_duplex(&running.conn, id).await.unwrap();
+            tx.send(&Frame { ver, ..frame(1, 5, "x") }).await.unwrap();
+            assert!(rx.next_frame().await.unwrap().is_some());
+            match ver {
+                1 => tx.cancel().await.unwrap(),
+                _ => drop(tx),
+            }
+            assert_eq!(cancelled.recv().await, Some(ver));
+        }
+
+        // No handler is left running to hold up shutdown
+        running.shutdown.cancel();
+        running.serving.await.unwrap().unwrap();
+    }
+}
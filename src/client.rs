@@ -0,0 +1,344 @@
+//! Client bootstrap and typed handles for bidirectional frame channels.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use nwd1::Frame;
+use quinn::{Connection, Endpoint, RecvStream, SendStream, TransportConfig, WriteError};
+use tokio::time::Instant;
+
+use crate::interceptor::clone_frame;
+use crate::{
+    FrameLimits, FrameReader, Headers, InterceptorStack, Nwd1QuicError, RpcClient, TraceContext,
+    deadline, propagate, propagating, send_frame, send_frames, set_headers, take_headers, trace,
+};
+
+/// Interval of keep-alive packets sent on idle client connections.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// Silence after which a client connection is considered lost.
+const MAX_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Transport settings [`Nwd1Client::connect`] applies: keep-alives every 10 seconds and a
+/// 30-second idle timeout, so idle channels stay open and a vanished server is noticed.
+pub fn default_transport_config() -> TransportConfig {
+    let mut transport = TransportConfig::default();
+    transport.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    transport.max_idle_timeout(Some(MAX_IDLE_TIMEOUT.try_into().expect("idle timeout in range")));
+    transport
+}
+
+/// A connection to an nwd1 server together with the endpoint driving it.
+#[derive(Debug)]
+pub struct Nwd1Client {
+    endpoint: Endpoint,
+    conn: Connection,
+}
+
+impl Nwd1Client {
+    /// Connect to `addr`, verifying its certificate against `server_name`.
+    ///
+    /// Binds a client endpoint to an ephemeral port. Any transport settings of `config` are
+    /// replaced with [`default_transport_config`]; use
+    /// [`connect_with_transport`](Self::connect_with_transport) to choose others.
+    pub async fn connect(
+        addr: SocketAddr,
+        server_name: &str,
+        config: quinn::ClientConfig,
+    ) -> Result<Self, Nwd1QuicError> {
+        let transport = Arc::new(default_transport_config());
+        Self::connect_with_transport(addr, server_name, config, transport).await
+    }
+
+    /// Connect like [`connect`](Self::connect), with `transport` as the transport settings.
+    pub async fn connect_with_transport(
+        addr: SocketAddr,
+        server_name: &str,
+        mut config: quinn::ClientConfig,
+        transport: Arc<TransportConfig>,
+    ) -> Result<Self, Nwd1QuicError> {
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        config.transport_config(transport);
+
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(config);
+        let conn = endpoint.connect(addr, server_name)?.await?;
+        Ok(Self { endpoint, conn })
+    }
+
+    /// Wrap a connection established on `endpoint`.
+    pub fn from_parts(endpoint: Endpoint, conn: Connection) -> Self {
+        Self { endpoint, conn }
+    }
+
+    /// Open a bidirectional stream as a sender / receiver pair.
+    pub async fn open_channel(&self) -> Result<(FrameSender, FrameReceiver), Nwd1QuicError> {
+        let (send, recv) = self.conn.open_bi().await?;
+        Ok((FrameSender::new(send), FrameReceiver::new(recv)))
+    }
+
+    /// Open a bidirectional stream for request/response calls.
+    pub async fn open_rpc(&self) -> Result<RpcClient, Nwd1QuicError> {
+        let (sender, receiver) = self.open_channel().await?;
+        Ok(RpcClient::new(sender, receiver))
+    }
+
+    /// The underlying connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Close the connection and wait for the close to reach the server.
+    pub async fn close(self) {
+        self.conn.close(0u32.into(), b"");
+        self.endpoint.wait_idle().await;
+    }
+}
+
+/// The send half of a frame channel.
+///
+/// Frames sent with a [deadline](crate::with_deadline) in scope carry the time left until it,
+/// and frames sent with a [trace context](crate::with_trace_context) in scope carry it as a
+/// [`TRACEPARENT`](crate::TRACEPARENT) header.
+#[derive(Debug)]
+pub struct FrameSender {
+    stream: SendStream,
+    interceptors: InterceptorStack,
+}
+
+impl FrameSender {
+    /// Wrap a send stream.
+    pub fn new(stream: SendStream) -> Self {
+        Self { stream, interceptors: InterceptorStack::new() }
+    }
+
+    /// Run every frame sent from now on through `interceptors`.
+    pub fn set_interceptors(&mut self, interceptors: InterceptorStack) {
+        self.interceptors = interceptors;
+    }
+
+    /// Send one frame; see [`send_frame`].
+    pub async fn send(&mut self, frame: &Frame) -> Result<(), Nwd1QuicError> {
+        if self.interceptors.is_empty() {
+            let forwarded = propagate(frame)?;
+            return send_frame(&mut self.stream, forwarded.as_ref().unwrap_or(frame)).await;
+        }
+        match self.interceptors.on_send(clone_frame(frame))? {
+            Some(frame) => {
+                let frame = propagate(&frame)?.unwrap_or(frame);
+                send_frame(&mut self.stream, &frame).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Send one frame carrying `headers`; see [`set_headers`](crate::set_headers).
+    pub async fn send_with_headers(
+        &mut self,
+        frame: &Frame,
+        headers: &Headers,
+    ) -> Result<(), Nwd1QuicError> {
+        let mut frame = clone_frame(frame);
+        set_headers(&mut frame, headers)?;
+        self.send(&frame).await
+    }
+
+    /// Send a burst of frames in as few writes as possible; see [`send_frames`].
+    ///
+    /// If an interceptor rejects a frame, none of the burst is sent.
+    pub async fn send_all<'a>(
+        &mut self,
+        frames: impl IntoIterator<Item = &'a Frame>,
+    ) -> Result<(), Nwd1QuicError> {
+        if self.interceptors.is_empty() && !propagating() {
+            return send_frames(&mut self.stream, frames).await;
+        }
+        let mut passed = Vec::new();
+        for frame in frames {
+            if let Some(frame) = self.interceptors.on_send(clone_frame(frame))? {
+                passed.push(propagate(&frame)?.unwrap_or(frame));
+            }
+        }
+        send_frames(&mut self.stream, &passed).await
+    }
+
+    /// Finish the stream; the peer's receiver ends after the frames already sent.
+    pub fn finish(&mut self) -> Result<(), Nwd1QuicError> {
+        self.stream.finish().map_err(|e| WriteError::from(e).into())
+    }
+
+    /// Abandon the stream, signalling `code` to the peer.
+    pub fn reset(&mut self, code: u32) -> Result<(), Nwd1QuicError> {
+        self.stream.reset(code.into()).map_err(|e| WriteError::from(e).into())
+    }
+
+    /// Consume the handle, returning the underlying stream.
+    pub fn into_inner(self) -> SendStream {
+        self.stream
+    }
+}
+
+/// The receive half of a frame channel.
+///
+/// Built on [`FrameReader`], so [`recv`](Self::recv) is cancel safe.
+#[derive(Debug)]
+pub struct FrameReceiver {
+    reader: FrameReader,
+    interceptors: InterceptorStack,
+    deadline: Option<Instant>,
+    trace: Option<TraceContext>,
+}
+
+impl FrameReceiver {
+    /// Wrap a receive stream.
+    pub fn new(stream: RecvStream) -> Self {
+        Self {
+            reader: FrameReader::new(stream),
+            interceptors: InterceptorStack::new(),
+            deadline: None,
+            trace: None,
+        }
+    }
+
+    /// Run every frame received from now on through `interceptors`.
+    pub fn set_interceptors(&mut self, interceptors: InterceptorStack) {
+        self.interceptors = interceptors;
+    }
+
+    /// Receive the next frame, or `None` once the peer finished the stream.
+    ///
+    /// Frames dropped by an interceptor or received after their deadline are skipped; a
+    /// rejected frame is returned as the interceptor's error, and receiving can continue after
+    /// it. A frame's deadline and [`TRACEPARENT`](crate::TRACEPARENT) header are stripped from
+    /// its payload and kept as [`deadline`](Self::deadline) and
+    /// [`trace_context`](Self::trace_context).
+    pub async fn recv(&mut self) -> Result<Option<Frame>, Nwd1QuicError> {
+        self.recv_outcome().await?.transpose()
+    }
+
+    /// Receive the next frame like [`recv`](Self::recv), keeping the errors of a single frame,
+    /// after which receiving can continue, apart from those that end the stream.
+    pub(crate) async fn recv_outcome(
+        &mut self,
+    ) -> Result<Option<Result<Frame, Nwd1QuicError>>, Nwd1QuicError> {
+        loop {
+            let Some(frame) = self.reader.next_frame().await? else { return Ok(None) };
+            match self.arrive(frame) {
+                Ok(None) => continue,
+                outcome => return Ok(outcome.transpose()),
+            }
+        }
+    }
+
+    /// Strip a received frame's extensions and run it through the interceptors, returning
+    /// `None` if it is to be skipped.
+    fn arrive(&mut self, mut frame: Frame) -> Result<Option<Frame>, Nwd1QuicError> {
+        let deadline = deadline::arrive(&mut frame)?;
+        if deadline::expired(deadline) {
+            return Ok(None);
+        }
+        let trace = trace::arrive(&mut frame);
+        let Some(frame) = self.interceptors.on_recv(frame)? else { return Ok(None) };
+        self.deadline = deadline;
+        self.trace = trace;
+        Ok(Some(frame))
+    }
+
+    /// Receive the next frame like [`recv`](Self::recv), with the headers it carried removed
+    /// from its payload; see [`take_headers`](crate::take_headers).
+    pub async fn recv_with_headers(&mut self) -> Result<Option<(Frame, Headers)>, Nwd1QuicError> {
+        let Some(mut frame) = self.recv().await? else { return Ok(None) };
+        let headers = take_headers(&mut frame)?;
+        Ok(Some((frame, headers)))
+    }
+
+    /// The deadline of the frame last returned by [`recv`](Self::recv), if it carried one.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The trace context of the frame last returned by [`recv`](Self::recv), if it carried
+    /// one.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.trace
+    }
+
+    /// Replace the limits applied to received frames.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.reader.set_limits(limits);
+    }
+
+    /// Consume the handle, returning the underlying reader.
+    pub fn into_inner(self) -> FrameReader {
+        self.reader
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{configs, frame, localhost};
+    use crate::{Nwd1Server, ReplySender};
+
+    #[tokio::test]
+    async fn channel_roundtrip() {
+        let (server_config, client_config) = configs();
+        let server = Nwd1Server::bind(localhost(), server_config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_token();
+        let echo = |frame: Frame, reply: ReplySender| async move { reply.send(&frame).await };
+        let serving = tokio::spawn(server.serve(echo));
+
+        let client = Nwd1Client::connect(addr, "localhost", client_config).await.unwrap();
+        let (mut tx, mut rx) = client.open_channel().await.unwrap();
+        let frames: Vec<Frame> = (0..3).map(|counter| frame(counter, 2, "hello")).collect();
+        tx.send(&frames[0]).await.unwrap();
+        tx.send_all(&frames[1..]).await.unwrap();
+        tx.finish().unwrap();
+
+        for counter in 0..3 {
+            let frame = rx.recv().await.unwrap().unwrap();
+            assert_eq!((frame.id.counter(), frame.kind), (counter, 2));
+        }
+        assert!(rx.recv().await.unwrap().is_none());
+
+        client.close().await;
+        shutdown.cancel();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_custom_transport() {
+        let (server_config, client_config) = configs();
+        let server = Nwd1Server::bind(localhost(), server_config).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_token();
+        let serving = tokio::spawn(server.serve(|_: Frame, _: ReplySender| async { Ok(()) }));
+
+        // Without the default keep-alives, the short idle timeout closes the connection
+        let mut transport = TransportConfig::default();
+        transport.max_idle_timeout(Some(Duration::from_millis(200).try_into().unwrap()));
+        let client =
+            Nwd1Client::connect_with_transport(addr, "localhost", client_config, transport.into())
+                .await
+                .unwrap();
+        let closed = client.connection().closed().await;
+        assert!(matches!(closed, quinn::ConnectionError::TimedOut));
+
+        shutdown.cancel();
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_connect_errors() {
+        let (_, client_config) = configs();
+        let addr: SocketAddr = (Ipv4Addr::LOCALHOST, 0).into();
+        let Err(err) = Nwd1Client::connect(addr, "localhost", client_config).await else {
+            panic!("connected to port 0");
+        };
+        assert!(matches!(err, Nwd1QuicError::Connect(_)));
+    }
+}
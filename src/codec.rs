@@ -0,0 +1,187 @@
+//! [`tokio_util::codec`] support for `nwd1` frames.
+//!
+//! [`Nwd1Codec`] shares its header validation with [`recv_frame`](crate::recv_frame), so frames
+//! can be read and written through `FramedRead` / `FramedWrite` over any `AsyncRead` /
+//! `AsyncWrite` transport, including quinn streams.
+
+use bytes::{Buf, BytesMut};
+use nwd1::Frame;
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::{
+    FIXED_LEN, FrameLimits, HEADER_LEN, Nwd1QuicError, check_prefix, decode_body, encode_into,
+};
+
+/// Codec that decodes and encodes `nwd1` frames.
+///
+/// Decoding checks `MAGIC` and the [`FrameLimits`] as soon as the fixed 25-byte prefix is
+/// buffered, before reserving room for the payload.
+#[derive(Debug, Default, Clone)]
+pub struct Nwd1Codec {
+    limits: FrameLimits,
+}
+
+impl Nwd1Codec {
+    /// Create a new codec with the default limits.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new codec enforcing `limits` on decoded frames.
+    pub fn with_limits(limits: FrameLimits) -> Self {
+        Self { limits }
+    }
+
+    /// The limits enforced on decoded frames.
+    pub fn limits(&self) -> &FrameLimits {
+        &self.limits
+    }
+}
+
+impl Decoder for Nwd1Codec {
+    type Item = Frame;
+    type Error = Nwd1QuicError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Frame>, Nwd1QuicError> {
+        let Some(len) = check_prefix(src, &self.limits)? else {
+            src.reserve(FIXED_LEN - src.len());
+            return Ok(None);
+        };
+
+        let total = HEADER_LEN + len;
+        if src.len() < total {
+            src.reserve(total - src.len());
+            return Ok(None);
+        }
+
+        src.advance(HEADER_LEN);
+        Ok(Some(decode_body(src.split_to(len).freeze())))
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Frame>, Nwd1QuicError> {
+        if let Some(frame) = self.decode(buf)? {
+            return Ok(Some(frame));
+        }
+        if buf.is_empty() {
+            return Ok(None);
+        }
+
+        // `decode` accepted the prefix so far, so LEN is trustworthy once the header is complete
+        let expected = match buf.first_chunk::<HEADER_LEN>() {
+            Some(header) => {
+                HEADER_LEN
+                    + u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize
+            }
+            None => HEADER_LEN,
+        };
+        Err(Nwd1QuicError::Truncated { expected, got: buf.len() })
+    }
+}
+
+impl Encoder<Frame> for Nwd1Codec {
+    type Error = Nwd1QuicError;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Nwd1QuicError> {
+        encode_into(&frame, dst);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use futures::{SinkExt, StreamExt};
+    use netid64::NetId64;
+    use nwd1::encode;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    use crate::test_util::frame;
+
+    #[test]
+    fn encode_matches_nwd1() {
+        let mut dst = BytesMut::new();
+        Nwd1Codec::new().encode(frame(42, 1, "ping"), &mut dst).unwrap();
+        assert_eq!(&dst[..], &encode(&frame(42, 1, "ping"))[..]);
+    }
+
+    #[test]
+    fn decode_waits_for_full_frame() {
+        let data = encode(&frame(42, 1, "ping"));
+        let mut codec = Nwd1Codec::new();
+        let mut src = BytesMut::new();
+
+        src.extend_from_slice(&data[..5]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&data[5..data.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(&data[data.len() - 1..]);
+
+        let decoded = codec.decode(&mut src).unwrap().unwrap();
+        assert_eq!(decoded.id.raw(), NetId64::make(1, 7, 42).raw());
+        assert_eq!(decoded.payload, Bytes::from_static(b"ping"));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn decode_rejects_bad_header() {
+        let mut src = BytesMut::from(&b"NWD2\0\0\0\x20"[..]);
+        let Err(err) = Nwd1Codec::new().decode(&mut src) else { panic!("bad header accepted") };
+        assert!(matches!(err, Nwd1QuicError::BadMagic(seen) if &seen == b"NWD2"));
+
+        let mut src = BytesMut::from(&b"NWD1\xff\xff\xff\xff"[..]);
+        let Err(err) = Nwd1Codec::new().decode(&mut src) else { panic!("bad header accepted") };
+        assert!(matches!(err, Nwd1QuicError::FrameTooLarge { len: 0xffff_ffff, .. }));
+    }
+
+    #[test]
+    fn decode_applies_limits_before_payload() {
+        let mut limits = FrameLimits::default();
+        limits.kind_max_len.insert(1, 32);
+        let mut codec = Nwd1Codec::with_limits(limits);
+
+        // Only the fixed prefix of an oversized kind-1 frame has arrived
+        let data = encode(&frame(42, 1, vec![0; 64]));
+        let mut src = BytesMut::from(&data[..FIXED_LEN]);
+        let Err(err) = codec.decode(&mut src) else { panic!("oversized kind accepted") };
+        assert!(matches!(err, Nwd1QuicError::FrameTooLarge { len: 81, limit: 32 }));
+        assert!(src.capacity() < data.len());
+    }
+
+    #[test]
+    fn decode_eof_reports_truncation() {
+        let data = encode(&frame(42, 1, "ping"));
+        let mut src = BytesMut::from(&data[..data.len() - 2]);
+        let Err(err) = Nwd1Codec::new().decode_eof(&mut src) else {
+            panic!("truncated frame accepted")
+        };
+        assert!(matches!(err, Nwd1QuicError::Truncated { expected: 29, got: 27 }));
+
+        let mut src = BytesMut::from(&data[..3]);
+        let Err(err) = Nwd1Codec::new().decode_eof(&mut src) else {
+            panic!("truncated frame accepted")
+        };
+        assert!(matches!(err, Nwd1QuicError::Truncated { expected: 8, got: 3 }));
+    }
+
+    #[tokio::test]
+    async fn framed_roundtrip() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut sink = FramedWrite::new(client, Nwd1Codec::new());
+        let mut stream = FramedRead::new(server, Nwd1Codec::new());
+
+        let writer = tokio::spawn(async move {
+            for counter in 0..3 {
+                sink.send(frame(counter, 1, "hello nwd1 over a small pipe")).await.unwrap();
+            }
+        });
+
+        for counter in 0..3 {
+            let decoded = stream.next().await.unwrap().unwrap();
+            assert_eq!(decoded.id.counter(), counter);
+            assert_eq!(decoded.payload, Bytes::from_static(b"hello nwd1 over a small pipe"));
+        }
+        writer.await.unwrap();
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,136 @@
+//! Frames carried in QUIC unreliable datagrams.
+
+use bytes::{Bytes, BytesMut};
+use nwd1::Frame;
+use quinn::{Connection, SendDatagramError};
+
+use crate::{
+    FIXED_LEN, FrameLimits, HEADER_LEN, Nwd1QuicError, check_prefix, decode_body, encode_into,
+};
+
+/// Send `frame` as a single QUIC datagram.
+///
+/// Datagrams may be lost, duplicated or reordered, and one that is still queued may be dropped to
+/// make room for a newer one. Frames whose encoding exceeds
+/// [`Connection::max_datagram_size`] are rejected with [`Nwd1QuicError::DatagramTooLarge`]
+/// instead of being sent.
+pub fn send_frame_datagram(conn: &Connection, frame: &Frame) -> Result<(), Nwd1QuicError> {
+    let len = FIXED_LEN + frame.payload.len();
+    let max = conn.max_datagram_size().ok_or(Nwd1QuicError::DatagramsUnsupported)?;
+    if len > max {
+        return Err(Nwd1QuicError::DatagramTooLarge { len, max });
+    }
+
+    let mut buf = BytesMut::with_capacity(len);
+    encode_into(frame, &mut buf);
+    conn.send_datagram(buf.freeze()).map_err(|e| send_error(conn, len, e))
+}
+
+/// Map quinn's datagram send error for a datagram of `len` bytes.
+pub(crate) fn send_error(conn: &Connection, len: usize, e: SendDatagramError) -> Nwd1QuicError {
+    match e {
+        SendDatagramError::UnsupportedByPeer | SendDatagramError::Disabled => {
+            Nwd1QuicError::DatagramsUnsupported
+        }
+        // The path MTU estimate shrank since the size was checked
+        SendDatagramError::TooLarge => {
+            Nwd1QuicError::DatagramTooLarge { len, max: conn.max_datagram_size().unwrap_or(0) }
+        }
+        SendDatagramError::ConnectionLost(e) => Nwd1QuicError::ConnectionLost(e),
+    }
+}
+
+/// Receive the next frame sent with [`send_frame_datagram`].
+///
+/// Each datagram must hold exactly one frame; anything else is reported as a protocol error,
+/// after which the next datagram can still be received.
+pub async fn recv_frame_datagram(conn: &Connection) -> Result<Frame, Nwd1QuicError> {
+    recv_frame_datagram_with_limits(conn, &FrameLimits::default()).await
+}
+
+/// Receive a datagram frame like [`recv_frame_datagram`], enforcing `limits`.
+pub async fn recv_frame_datagram_with_limits(
+    conn: &Connection,
+    limits: &FrameLimits,
+) -> Result<Frame, Nwd1QuicError> {
+    let datagram = conn.read_datagram().await?;
+    decode_datagram(datagram, limits)
+}
+
+/// Decode a datagram holding exactly one frame, without copying the payload.
+pub(crate) fn decode_datagram(
+    mut datagram: Bytes,
+    limits: &FrameLimits,
+) -> Result<Frame, Nwd1QuicError> {
+    let got = datagram.len();
+    let Some(len) = check_prefix(&datagram, limits)? else {
+        let expected = if got < HEADER_LEN { HEADER_LEN } else { FIXED_LEN };
+        return Err(Nwd1QuicError::Truncated { expected, got });
+    };
+
+    let expected = HEADER_LEN + len;
+    if got < expected {
+        return Err(Nwd1QuicError::Truncated { expected, got });
+    }
+    if got > expected {
+        return Err(Nwd1QuicError::TrailingBytes { expected, got });
+    }
+    Ok(decode_body(datagram.split_off(HEADER_LEN)))
+}
+
+#[cfg(test)]
+mod tests {
+    use netid64::NetId64;
+    use nwd1::encode;
+
+    use super::*;
+    use crate::test_util::{frame, loopback};
+
+    #[tokio::test]
+    async fn datagram_roundtrip() {
+        let lb = loopback().await;
+        send_frame_datagram(&lb.client, &frame(5, 3, "telemetry")).unwrap();
+
+        let received = recv_frame_datagram(&lb.server).await.unwrap();
+        assert_eq!(received.id.raw(), NetId64::make(1, 7, 5).raw());
+        assert_eq!((received.kind, received.ver), (3, 1));
+        assert_eq!(received.payload, "telemetry");
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_frames() {
+        let lb = loopback().await;
+        let max = lb.client.max_datagram_size().unwrap();
+
+        let Err(err) = send_frame_datagram(&lb.client, &frame(5, 3, vec![0; max])) else {
+            panic!("oversize frame sent");
+        };
+        assert!(
+            matches!(err, Nwd1QuicError::DatagramTooLarge { len, max: m } if len == max + FIXED_LEN && m == max)
+        );
+        assert!(send_frame_datagram(&lb.client, &frame(5, 3, vec![0; max - FIXED_LEN])).is_ok());
+    }
+
+    #[test]
+    fn decode_validates_whole_datagram() {
+        let limits = FrameLimits::default();
+        let data = encode(&frame(5, 3, "abc"));
+        assert_eq!(decode_datagram(data.clone(), &limits).unwrap().payload, "abc");
+
+        let Err(err) = decode_datagram(data.slice(..data.len() - 1), &limits) else { panic!() };
+        assert!(matches!(err, Nwd1QuicError::Truncated { expected: 28, got: 27 }));
+
+        let Err(err) = decode_datagram(data.slice(..4), &limits) else { panic!() };
+        assert!(matches!(err, Nwd1QuicError::Truncated { expected: 8, got: 4 }));
+
+        let mut long = BytesMut::from(&data[..]);
+        long.extend_from_slice(b"xx");
+        let Err(err) = decode_datagram(long.freeze(), &limits) else { panic!() };
+        assert!(matches!(err, Nwd1QuicError::TrailingBytes { expected: 28, got: 30 }));
+
+        let Err(err) = decode_datagram(Bytes::from_static(b"HTTP/1.1 200 OK"), &limits) else {
+            panic!()
+        };
+        assert!(matches!(err, Nwd1QuicError::BadMagic(_)));
+    }
+}
@@ -0,0 +1,154 @@
+//! Deadline propagation: the time a caller still allows for a request, carried with it.
+//!
+//! [`DEADLINE_FLAG`] set in `VER` announces a 4-byte prefix to the payload holding the
+//! remaining budget in milliseconds (u32, BE). The budget is relative, so hosts need no
+//! synchronised clocks; time spent in flight is not deducted.
+//!
+//! [`FrameReceiver`](crate::FrameReceiver) and [`Nwd1Server`](crate::Nwd1Server) strip the
+//! prefix from received frames and drop frames whose deadline passed before they were
+//! delivered. Handlers run with their frame's deadline in scope ([`with_deadline`]), and frames
+//! sent from that scope through [`FrameSender`](crate::FrameSender),
+//! [`RpcClient`](crate::RpcClient) or [`CallSender`](crate::CallSender) carry what is left of it.
+
+use std::future::Future;
+use std::time::Duration;
+
+use bytes::{BufMut, BytesMut};
+use nwd1::Frame;
+use tokio::time::Instant;
+
+use crate::Nwd1QuicError;
+use crate::interceptor::clone_frame;
+
+/// `VER` bit announcing a deadline prefix in the payload.
+pub const DEADLINE_FLAG: u64 = 1 << 62;
+
+pub(crate) const DEADLINE_LEN: usize = 4;
+
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+/// Attach `budget` to `frame`, replacing any budget it already carries.
+///
+/// The budget is truncated to whole milliseconds and capped at `u32::MAX` of them. Copies the
+/// payload.
+pub fn set_deadline(frame: &mut Frame, budget: Duration) {
+    let rest = if frame.ver & DEADLINE_FLAG != 0 {
+        frame.payload.slice(DEADLINE_LEN.min(frame.payload.len())..)
+    } else {
+        frame.payload.clone()
+    };
+    let millis = u32::try_from(budget.as_millis()).unwrap_or(u32::MAX);
+    let mut payload = BytesMut::with_capacity(DEADLINE_LEN + rest.len());
+    payload.put_u32(millis);
+    payload.extend_from_slice(&rest);
+    frame.payload = payload.freeze();
+    frame.ver |= DEADLINE_FLAG;
+}
+
+/// Remove the budget `frame` carries, restoring its original payload and `VER`.
+///
+/// Returns `None` if the frame has no deadline, and [`Nwd1QuicError::BadExtension`] if the
+/// payload is too short for the budget it announces.
+pub fn take_deadline(frame: &mut Frame) -> Result<Option<Duration>, Nwd1QuicError> {
+    let Some(budget) = budget(frame)? else { return Ok(None) };
+    frame.payload = frame.payload.slice(DEADLINE_LEN..);
+    frame.ver &= !DEADLINE_FLAG;
+    Ok(Some(budget))
+}
+
+/// The deadline of the frame being handled by the current task, if it carried one.
+pub fn current_deadline() -> Option<Instant> {
+    DEADLINE.try_with(|deadline| *deadline).ok()
+}
+
+/// Run `fut` with `deadline` in scope, or the current deadline if that is earlier.
+///
+/// Frames sent from `fut` through the high-level handles carry the time left until then.
+pub async fn with_deadline<F: Future>(deadline: Instant, fut: F) -> F::Output {
+    let deadline = current_deadline().map_or(deadline, |current| current.min(deadline));
+    DEADLINE.scope(deadline, fut).await
+}
+
+/// Run `fut` with `deadline` in scope, if there is one.
+pub(crate) async fn scoped<F: Future>(deadline: Option<Instant>, fut: F) -> F::Output {
+    match deadline {
+        Some(deadline) => with_deadline(deadline, fut).await,
+        None => fut.await,
+    }
+}
+
+fn budget(frame: &Frame) -> Result<Option<Duration>, Nwd1QuicError> {
+    if frame.ver & DEADLINE_FLAG == 0 {
+        return Ok(None);
+    }
+    let Some(millis) = frame.payload.first_chunk::<DEADLINE_LEN>() else {
+        return Err(Nwd1QuicError::BadExtension { flag: DEADLINE_FLAG });
+    };
+    Ok(Some(Duration::from_millis(u32::from_be_bytes(*millis).into())))
+}
+
+/// Strip the deadline from a received frame, returning when it passes.
+pub(crate) fn arrive(frame: &mut Frame) -> Result<Option<Instant>, Nwd1QuicError> {
+    Ok(take_deadline(frame)?.map(|budget| Instant::now() + budget))
+}
+
+/// Whether a received frame's deadline has passed.
+pub(crate) fn expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| deadline <= Instant::now())
+}
+
+/// `frame` with its budget cut to what is left of the current deadline, or `None` if no
+/// deadline is in scope or the frame's own budget is shorter.
+pub(crate) fn forward(frame: &Frame) -> Result<Option<Frame>, Nwd1QuicError> {
+    let Some(deadline) = current_deadline() else { return Ok(None) };
+    let left = deadline.saturating_duration_since(Instant::now());
+    if budget(frame)?.is_some_and(|budget| budget <= left) {
+        return Ok(None);
+    }
+    let mut frame = clone_frame(frame);
+    set_deadline(&mut frame, left);
+    Ok(Some(frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::frame;
+
+    #[test]
+    fn set_and_take_roundtrip() {
+        let mut f = frame(1, 1, "body");
+        set_deadline(&mut f, Duration::from_millis(1500));
+        set_deadline(&mut f, Duration::from_millis(250));
+        assert_eq!((f.ver, f.payload.len()), (1 | DEADLINE_FLAG, 8));
+
+        assert_eq!(take_deadline(&mut f).unwrap(), Some(Duration::from_millis(250)));
+        assert_eq!((f.ver, &f.payload[..]), (1, &b"body"[..]));
+        assert_eq!(take_deadline(&mut f).unwrap(), None);
+
+        let mut short = Frame { ver: DEADLINE_FLAG, ..frame(1, 1, "ab") };
+        assert!(matches!(take_deadline(&mut short), Err(Nwd1QuicError::BadExtension { .. })));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn forward_reduces_budget() {
+        let mut f = frame(1, 1, "body");
+        assert!(forward(&f).unwrap().is_none());
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        with_deadline(deadline, async {
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            let mut forwarded = forward(&f).unwrap().unwrap();
+            assert_eq!(take_deadline(&mut forwarded).unwrap(), Some(Duration::from_millis(60)));
+
+            // A shorter budget of the frame's own is kept, as is an earlier outer deadline
+            set_deadline(&mut f, Duration::from_millis(10));
+            assert!(forward(&f).unwrap().is_none());
+            let later = Instant::now() + Duration::from_secs(5);
+            with_deadline(later, async { assert_eq!(current_deadline(), Some(deadline)) }).await;
+        })
+        .await;
+    }
+}
@@ -0,0 +1,274 @@
+//! Error type shared by every send and receive path.
+
+use std::fmt;
+
+use quinn::{ConnectError, ConnectionError, ReadError, WriteError};
+
+/// Errors returned by nwd1-quic.
+///
+/// Protocol violations by the peer ([`is_protocol_error`](Self::is_protocol_error)) are kept
+/// apart from transport failures, so callers can e.g. reset a misbehaving stream but retry
+/// after a lost connection.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Nwd1QuicError {
+    /// The frame did not start with `MAGIC`; carries the four bytes seen instead.
+    BadMagic([u8; 4]),
+    /// `LEN` exceeds the applicable [`FrameLimits`](crate::FrameLimits) maximum.
+    FrameTooLarge { len: usize, limit: usize },
+    /// `LEN` is below the applicable [`FrameLimits`](crate::FrameLimits) minimum.
+    FrameTooSmall { len: usize, min: usize },
+    /// `VER` is outside the range accepted by [`FrameLimits`](crate::FrameLimits).
+    VersionNotAllowed { ver: u64 },
+    /// The input ended after `got` of the `expected` bytes of a frame.
+    Truncated { expected: usize, got: usize },
+    /// A datagram held `got` bytes but the frame it starts with is `expected` bytes long.
+    TrailingBytes { expected: usize, got: usize },
+    /// A datagram fragment numbered `index` of `count` does not fit the frame it belongs to.
+    BadFragment { index: u16, count: u16 },
+    /// The peer does not support datagrams, or they are disabled locally.
+    DatagramsUnsupported,
+    /// An encoded frame of `len` bytes exceeds the connection's `max` datagram size.
+    DatagramTooLarge { len: usize, max: usize },
+    /// A payload extension announced by a `VER` flag bit, e.g.
+    /// [`HEADERS_FLAG`](crate::HEADERS_FLAG), is missing or malformed.
+    BadExtension { flag: u64 },
+    /// No handler is routed for the frame's `KIND`.
+    UnknownKind { kind: u8 },
+    /// A data frame used a `KIND` reserved for terminal frames, see
+    /// [`is_terminal_kind`](crate::is_terminal_kind).
+    ReservedKind { kind: u8 },
+    /// Reading from a stream failed.
+    Read(ReadError),
+    /// Writing to a stream failed.
+    Write(WriteError),
+    /// A connection could not be started, e.g. because the address or server name is invalid.
+    Connect(ConnectError),
+    /// An [`Interceptor`](crate::Interceptor) refused the frame.
+    Rejected(Box<dyn std::error::Error + Send + Sync>),
+    /// A [`tower::Service`] failed.
+    Service(Box<dyn std::error::Error + Send + Sync>),
+    /// The peer aborted the call with an `ERROR` frame carrying `message`.
+    Remote { message: String },
+    /// The call was cancelled.
+    Cancelled,
+    /// No response arrived within the call's timeout.
+    Timeout,
+    /// A call with the same request `ID` is already waiting for its response.
+    DuplicateRequestId { id: u64 },
+    /// The channel closed before the operation completed.
+    Closed,
+    /// The connection was closed or lost.
+    ConnectionLost(ConnectionError),
+    /// I/O error from a transport other than a quinn stream.
+    Io(std::io::Error),
+}
+
+impl Nwd1QuicError {
+    /// Whether the peer sent bytes that violate the framing or the configured limits.
+    pub fn is_protocol_error(&self) -> bool {
+        matches!(
+            self,
+            Self::BadMagic(_)
+                | Self::FrameTooLarge { .. }
+                | Self::FrameTooSmall { .. }
+                | Self::VersionNotAllowed { .. }
+                | Self::Truncated { .. }
+                | Self::TrailingBytes { .. }
+                | Self::BadFragment { .. }
+                | Self::BadExtension { .. }
+                | Self::UnknownKind { .. }
+        )
+    }
+
+    /// Whether the underlying connection is gone.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, Self::ConnectionLost(_))
+    }
+
+    /// A copy of the error for each of the calls it fails; boxed and I/O errors keep only their
+    /// message.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::BadMagic(seen) => Self::BadMagic(*seen),
+            Self::FrameTooLarge { len, limit } => Self::FrameTooLarge { len: *len, limit: *limit },
+            Self::FrameTooSmall { len, min } => Self::FrameTooSmall { len: *len, min: *min },
+            Self::VersionNotAllowed { ver } => Self::VersionNotAllowed { ver: *ver },
+            Self::Truncated { expected, got } => Self::Truncated { expected: *expected, got: *got },
+            Self::TrailingBytes { expected, got } => {
+                Self::TrailingBytes { expected: *expected, got: *got }
+            }
+            Self::BadFragment { index, count } => {
+                Self::BadFragment { index: *index, count: *count }
+            }
+            Self::DatagramsUnsupported => Self::DatagramsUnsupported,
+            Self::DatagramTooLarge { len, max } => Self::DatagramTooLarge { len: *len, max: *max },
+            Self::BadExtension { flag } => Self::BadExtension { flag: *flag },
+            Self::UnknownKind { kind } => Self::UnknownKind { kind: *kind },
+            Self::ReservedKind { kind } => Self::ReservedKind { kind: *kind },
+            Self::Read(e) => Self::Read(e.clone()),
+            Self::Write(e) => Self::Write(e.clone()),
+            Self::Connect(e) => Self::Connect(e.clone()),
+            Self::Rejected(e) => Self::Rejected(e.to_string().into()),
+            Self::Service(e) => Self::Service(e.to_string().into()),
+            Self::Remote { message } => Self::Remote { message: message.clone() },
+            Self::Cancelled => Self::Cancelled,
+            Self::Timeout => Self::Timeout,
+            Self::DuplicateRequestId { id } => Self::DuplicateRequestId { id: *id },
+            Self::Closed => Self::Closed,
+            Self::ConnectionLost(e) => Self::ConnectionLost(e.clone()),
+            Self::Io(e) => Self::Io(std::io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Nwd1QuicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadMagic(seen) => write!(f, "nwd1 bad magic {seen:02x?}"),
+            Self::FrameTooLarge { len, limit } => {
+                write!(f, "nwd1 frame too large ({len} bytes, limit {limit})")
+            }
+            Self::FrameTooSmall { len, min } => {
+                write!(f, "nwd1 frame too short ({len} bytes, minimum {min})")
+            }
+            Self::VersionNotAllowed { ver } => write!(f, "nwd1 version {ver} not allowed"),
+            Self::Truncated { expected, got } => {
+                write!(f, "nwd1 frame truncated ({got} of {expected} bytes)")
+            }
+            Self::TrailingBytes { expected, got } => {
+                write!(f, "nwd1 datagram has {got} bytes for a {expected}-byte frame")
+            }
+            Self::BadFragment { index, count } => {
+                write!(f, "nwd1 bad datagram fragment {index} of {count}")
+            }
+            Self::DatagramsUnsupported => write!(f, "datagrams unsupported on this connection"),
+            Self::DatagramTooLarge { len, max } => {
+                write!(f, "nwd1 frame too large for a datagram ({len} bytes, max {max})")
+            }
+            Self::BadExtension { flag } => write!(f, "nwd1 malformed extension {flag:#x}"),
+            Self::UnknownKind { kind } => write!(f, "nwd1 frame kind {kind} not handled"),
+            Self::ReservedKind { kind } => write!(f, "nwd1 frame kind {kind:#x} is reserved"),
+            Self::Read(e) => write!(f, "read error: {e}"),
+            Self::Write(e) => write!(f, "write error: {e}"),
+            Self::Rejected(e) => write!(f, "frame rejected: {e}"),
+            Self::Service(e) => write!(f, "service error: {e}"),
+            Self::Remote { message } => write!(f, "remote error: {message}"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::DuplicateRequestId { id } => write!(f, "request id {id:#x} already pending"),
+            Self::Closed => write!(f, "channel closed"),
+            Self::Connect(e) => write!(f, "connect error: {e}"),
+            Self::ConnectionLost(e) => write!(f, "connection lost: {e}"),
+            Self::Io(e) => write!(f, "i/o error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Nwd1QuicError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Read(e) => Some(e),
+            Self::Write(e) => Some(e),
+            Self::Connect(e) => Some(e),
+            Self::Rejected(e) | Self::Service(e) => Some(&**e),
+            Self::ConnectionLost(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ReadError> for Nwd1QuicError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::ConnectionLost(e) => Self::ConnectionLost(e),
+            e => Self::Read(e),
+        }
+    }
+}
+
+impl From<WriteError> for Nwd1QuicError {
+    fn from(e: WriteError) -> Self {
+        match e {
+            WriteError::ConnectionLost(e) => Self::ConnectionLost(e),
+            e => Self::Write(e),
+        }
+    }
+}
+
+impl From<ConnectError> for Nwd1QuicError {
+    fn from(e: ConnectError) -> Self {
+        Self::Connect(e)
+    }
+}
+
+impl From<ConnectionError> for Nwd1QuicError {
+    fn from(e: ConnectionError) -> Self {
+        Self::ConnectionLost(e)
+    }
+}
+
+/// Recovers quinn's typed errors from the `io::Error`s its `AsyncRead` / `AsyncWrite` impls
+/// return; anything else becomes [`Nwd1QuicError::Io`].
+impl From<std::io::Error> for Nwd1QuicError {
+    fn from(e: std::io::Error) -> Self {
+        if let Some(inner) = e.get_ref() {
+            if let Some(read) = inner.downcast_ref::<ReadError>() {
+                return read.clone().into();
+            }
+            if let Some(write) = inner.downcast_ref::<WriteError>() {
+                return write.clone().into();
+            }
+        }
+        Self::Io(e)
+    }
+}
+
+impl From<Nwd1QuicError> for std::io::Error {
+    fn from(e: Nwd1QuicError) -> Self {
+        use std::io::ErrorKind;
+        match e {
+            Nwd1QuicError::Io(e) => e,
+            Nwd1QuicError::Read(e) => e.into(),
+            Nwd1QuicError::Write(e) => e.into(),
+            Nwd1QuicError::ConnectionLost(_) => std::io::Error::new(ErrorKind::NotConnected, e),
+            Nwd1QuicError::Timeout => std::io::Error::new(ErrorKind::TimedOut, e),
+            Nwd1QuicError::Rejected(_) => std::io::Error::new(ErrorKind::PermissionDenied, e),
+            Nwd1QuicError::Closed => std::io::Error::new(ErrorKind::BrokenPipe, e),
+            Nwd1QuicError::Truncated { .. } => std::io::Error::new(ErrorKind::UnexpectedEof, e),
+            Nwd1QuicError::DatagramsUnsupported => std::io::Error::new(ErrorKind::Unsupported, e),
+            Nwd1QuicError::DatagramTooLarge { .. } | Nwd1QuicError::ReservedKind { .. } => {
+                std::io::Error::new(ErrorKind::InvalidInput, e)
+            }
+            e => std::io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_quinn_errors_from_io() {
+        let io: std::io::Error = ReadError::Reset(7u32.into()).into();
+        assert!(matches!(Nwd1QuicError::from(io), Nwd1QuicError::Read(ReadError::Reset(_))));
+
+        let io: std::io::Error = ReadError::ConnectionLost(ConnectionError::TimedOut).into();
+        assert!(Nwd1QuicError::from(io).is_connection_lost());
+
+        let io = std::io::Error::other("pipe");
+        assert!(matches!(Nwd1QuicError::from(io), Nwd1QuicError::Io(_)));
+    }
+
+    #[test]
+    fn classifies_protocol_errors() {
+        assert!(Nwd1QuicError::BadMagic(*b"HTTP").is_protocol_error());
+        assert!(Nwd1QuicError::Truncated { expected: 25, got: 3 }.is_protocol_error());
+        assert!(!Nwd1QuicError::Write(WriteError::ClosedStream).is_protocol_error());
+
+        let io = std::io::Error::from(Nwd1QuicError::FrameTooLarge { len: 10, limit: 5 });
+        assert_eq!(io.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
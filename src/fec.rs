@@ -0,0 +1,380 @@
+//! XOR-parity forward error correction over groups of datagrams.
+//!
+//! Every datagram of a group is wrapped as `FEC_MAGIC(4) | GROUP(4, BE) | INDEX(1) | DATAGRAM`.
+//! After `group_size` datagrams (or on flush) the sender adds a parity datagram,
+//! `FEC_MAGIC(4) | GROUP(4, BE) | 0xFF | COUNT(1) | PARITY`, where `PARITY` is the XOR of every
+//! member's `LEN(2, BE) | DATAGRAM`, zero-padded to the longest member. Any single lost datagram
+//! of a group can be rebuilt from the others and the parity.
+
+use std::collections::{HashMap, VecDeque};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::Nwd1QuicError;
+
+/// Magic of a datagram carried under forward error correction.
+pub const FEC_MAGIC: &[u8; 4] = b"NWDE";
+
+const FEC_HEADER_LEN: usize = 4 + 4 + 1; // FEC_MAGIC + GROUP + INDEX
+const PARITY_INDEX: u8 = 0xFF;
+
+/// Bytes [`FecEncoder`] may add to the largest datagram of a group, in its parity datagram.
+pub const FEC_OVERHEAD: usize = FEC_HEADER_LEN + 1 + 2; // + COUNT + LEN
+
+/// Forward error correction settings, shared by both ends of a connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FecConfig {
+    /// Datagrams per parity datagram, at most 254. Smaller groups recover more losses at a
+    /// higher bandwidth cost. Defaults to 4.
+    pub group_size: u8,
+    /// Number of recent groups the decoder keeps for recovery. Defaults to 64.
+    pub window: usize,
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        Self { group_size: 4, window: 64 }
+    }
+}
+
+/// Snapshot of [`FecDecoder`] counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FecStats {
+    /// Datagrams rebuilt from parity.
+    pub recovered: u64,
+    /// Datagrams lost in groups that missed more than one, which parity cannot rebuild. Counted
+    /// once the group leaves the decoder's window, so late arrivals can still complete it; a
+    /// group whose parity is lost too is taken to end at its last member received.
+    pub unrecoverable: u64,
+}
+
+/// Sans-IO encoder wrapping datagrams into parity-protected groups.
+#[derive(Debug)]
+pub struct FecEncoder {
+    group_size: u8,
+    group: u32,
+    count: u8,
+    parity: BytesMut,
+}
+
+impl FecEncoder {
+    /// Create an encoder starting at group 0.
+    pub fn new(config: &FecConfig) -> Self {
+        Self {
+            group_size: config.group_size.clamp(1, PARITY_INDEX - 1),
+            group: 0,
+            count: 0,
+            parity: BytesMut::new(),
+        }
+    }
+
+    /// Wrap `datagram`, pushing it to `out` followed by the group's parity once it is full.
+    pub fn encode(&mut self, datagram: &[u8], out: &mut Vec<Bytes>) {
+        let mut wrapped = BytesMut::with_capacity(FEC_HEADER_LEN + datagram.len());
+        wrapped.put_slice(FEC_MAGIC);
+        wrapped.put_u32(self.group);
+        wrapped.put_u8(self.count);
+        wrapped.put_slice(datagram);
+        out.push(wrapped.freeze());
+
+        xor_member(&mut self.parity, datagram);
+        self.count += 1;
+        if self.count == self.group_size {
+            self.flush(out);
+        }
+    }
+
+    /// Close the current group early, pushing its parity to `out` if it has any datagrams.
+    ///
+    /// Call this when the sender goes idle, so the last datagrams are protected too.
+    pub fn flush(&mut self, out: &mut Vec<Bytes>) {
+        if self.count == 0 {
+            return;
+        }
+        let mut parity = BytesMut::with_capacity(FEC_HEADER_LEN + 1 + self.parity.len());
+        parity.put_slice(FEC_MAGIC);
+        parity.put_u32(self.group);
+        parity.put_u8(PARITY_INDEX);
+        parity.put_u8(self.count);
+        parity.put_slice(&self.parity);
+        out.push(parity.freeze());
+
+        self.parity.clear();
+        self.count = 0;
+        self.group = self.group.wrapping_add(1);
+    }
+}
+
+/// XOR `LEN | datagram` into `acc`, growing it as needed.
+fn xor_member(acc: &mut BytesMut, datagram: &[u8]) {
+    let len = 2 + datagram.len();
+    if acc.len() < len {
+        acc.resize(len, 0);
+    }
+    let prefix = (datagram.len() as u16).to_be_bytes();
+    for (a, b) in acc.iter_mut().zip(prefix.iter().chain(datagram)) {
+        *a ^= b;
+    }
+}
+
+/// Sans-IO decoder unwrapping datagrams from [`FecEncoder`] and rebuilding single losses.
+#[derive(Debug)]
+pub struct FecDecoder {
+    window: usize,
+    groups: HashMap<u32, Group>,
+    order: VecDeque<u32>,
+    /// The most recent group seen, which decides when older ones have left the window.
+    newest: Option<u32>,
+    stats: FecStats,
+}
+
+#[derive(Debug, Default)]
+struct Group {
+    members: HashMap<u8, Bytes>,
+    /// One past the highest member index received.
+    end: u8,
+    /// The member count and parity, once the parity arrived.
+    parity: Option<(u8, Bytes)>,
+}
+
+impl Group {
+    /// Members known to be missing, i.e. not received or rebuilt although the parity or a later
+    /// member arrived.
+    fn missing(&self) -> Vec<u8> {
+        let count = self.parity.as_ref().map_or(self.end, |(count, _)| *count);
+        (0..count).filter(|i| !self.members.contains_key(i)).collect()
+    }
+}
+
+impl FecDecoder {
+    /// Create a decoder keeping `config.window` groups.
+    pub fn new(config: &FecConfig) -> Self {
+        Self {
+            window: config.window.max(1),
+            groups: HashMap::new(),
+            order: VecDeque::new(),
+            newest: None,
+            stats: FecStats::default(),
+        }
+    }
+
+    /// Process one received datagram, pushing the datagram it carries and any it lets the
+    /// decoder rebuild to `out`.
+    ///
+    /// A group stays open until it leaves the window, so a member arriving after the parity is
+    /// still delivered and may complete the recovery of another. Duplicates of already
+    /// delivered datagrams and datagrams of groups that left the window push nothing.
+    pub fn decode(
+        &mut self,
+        mut datagram: Bytes,
+        out: &mut Vec<Bytes>,
+    ) -> Result<(), Nwd1QuicError> {
+        if datagram.len() < FEC_HEADER_LEN {
+            return Err(Nwd1QuicError::Truncated { expected: FEC_HEADER_LEN, got: datagram.len() });
+        }
+        let magic = datagram.split_to(4);
+        if magic != FEC_MAGIC[..] {
+            return Err(Nwd1QuicError::BadMagic([magic[0], magic[1], magic[2], magic[3]]));
+        }
+        let (id, index) = (datagram.get_u32(), datagram.get_u8());
+        if !self.groups.contains_key(&id) && self.has_left_window(id) {
+            return Ok(());
+        }
+
+        if index != PARITY_INDEX {
+            let group = self.group(id);
+            if group.members.contains_key(&index) {
+                return Ok(());
+            }
+            group.end = group.end.max(index + 1);
+            group.members.insert(index, datagram.clone());
+            out.push(datagram);
+        } else {
+            if datagram.is_empty() {
+                return Err(Nwd1QuicError::Truncated {
+                    expected: FEC_HEADER_LEN + 1,
+                    got: FEC_HEADER_LEN,
+                });
+            }
+            let count = datagram.get_u8();
+            let group = self.group(id);
+            if group.parity.is_some() {
+                return Ok(());
+            }
+            group.parity = Some((count, datagram));
+        }
+        self.recover(id, out)
+    }
+
+    /// Rebuild the member of group `id` if it is the only one missing.
+    fn recover(&mut self, id: u32, out: &mut Vec<Bytes>) -> Result<(), Nwd1QuicError> {
+        let Some(group) = self.groups.get_mut(&id) else { return Ok(()) };
+        let [lost] = group.missing()[..] else { return Ok(()) };
+        let Some((count, parity)) = &group.parity else { return Ok(()) };
+
+        let mut acc = BytesMut::from(&parity[..]);
+        for member in group.members.iter().filter(|&(i, _)| i < count).map(|(_, m)| m) {
+            xor_member(&mut acc, member);
+        }
+        let len = u16::from_be_bytes([acc[0], acc[1]]) as usize;
+        if acc.len() < 2 + len {
+            return Err(Nwd1QuicError::Truncated { expected: 2 + len, got: acc.len() });
+        }
+        acc.advance(2);
+        acc.truncate(len);
+        let recovered = acc.freeze();
+        group.members.insert(lost, recovered.clone());
+        self.stats.recovered += 1;
+        out.push(recovered);
+        Ok(())
+    }
+
+    /// Current counters.
+    pub fn stats(&self) -> FecStats {
+        self.stats
+    }
+
+    /// Whether `group` is a full window older than the newest group, so it was forgotten.
+    fn has_left_window(&self, group: u32) -> bool {
+        let Some(newest) = self.newest else { return false };
+        let age = newest.wrapping_sub(group);
+        // Group numbers wrap, so anything more than half the range ahead is taken to be older
+        age < 1 << 31 && age as usize >= self.window
+    }
+
+    /// The state of `group`, forgetting the oldest group when the window is full and counting
+    /// the members it still misses as lost.
+    fn group(&mut self, group: u32) -> &mut Group {
+        if !self.groups.contains_key(&group) {
+            if self.newest.is_none_or(|newest| group.wrapping_sub(newest) < 1 << 31) {
+                self.newest = Some(group);
+            }
+            if self.order.len() == self.window
+                && let Some(oldest) = self.order.pop_front()
+                && let Some(expired) = self.groups.remove(&oldest)
+            {
+                self.stats.unrecoverable += expired.missing().len() as u64;
+            }
+            self.order.push_back(group);
+        }
+        self.groups.entry(group).or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `datagrams` through an encoder and decoder, dropping the encoded datagrams for which
+    /// `lose` returns true.
+    fn lossy_channel(
+        config: &FecConfig,
+        datagrams: &[Bytes],
+        lose: impl Fn(usize) -> bool,
+    ) -> (Vec<Bytes>, FecStats) {
+        let mut encoder = FecEncoder::new(config);
+        let mut wire = Vec::new();
+        datagrams.iter().for_each(|d| encoder.encode(d, &mut wire));
+        encoder.flush(&mut wire);
+
+        let mut decoder = FecDecoder::new(config);
+        let mut delivered = Vec::new();
+        for (_, d) in wire.into_iter().enumerate().filter(|(i, _)| !lose(*i)) {
+            decoder.decode(d, &mut delivered).unwrap();
+        }
+        (delivered, decoder.stats())
+    }
+
+    fn datagrams(n: usize) -> Vec<Bytes> {
+        (0..n).map(|i| Bytes::from(vec![i as u8; 10 + i * 7])).collect()
+    }
+
+    #[test]
+    fn recovers_single_loss_per_group() {
+        let config = FecConfig { group_size: 4, window: 8 };
+        let sent = datagrams(10);
+        // Groups are 4 data + 1 parity on the wire; the last one is 2 + 1 after the flush
+        let (mut delivered, stats) = lossy_channel(&config, &sent, |i| i == 1 || i == 8 || i == 10);
+
+        assert_eq!(stats, FecStats { recovered: 3, unrecoverable: 0 });
+        delivered.sort_by_key(|d| d[0]);
+        assert_eq!(delivered, sent);
+    }
+
+    #[test]
+    fn counts_unrecoverable_losses() {
+        let config = FecConfig { group_size: 4, window: 1 };
+        let sent = datagrams(5);
+        // The losses of the first group are counted once the second one pushes it out
+        let (delivered, stats) = lossy_channel(&config, &sent, |i| i == 0 || i == 2);
+
+        assert_eq!(stats, FecStats { recovered: 0, unrecoverable: 2 });
+        assert_eq!(delivered, [sent[1].clone(), sent[3].clone(), sent[4].clone()]);
+    }
+
+    #[test]
+    fn counts_losses_without_parity() {
+        let config = FecConfig { group_size: 4, window: 1 };
+        let sent = datagrams(8);
+        // Member 1 of the first group is lost along with its parity
+        let (delivered, stats) = lossy_channel(&config, &sent, |i| i == 1 || i == 4);
+
+        assert_eq!(stats, FecStats { recovered: 0, unrecoverable: 1 });
+        assert_eq!(delivered.len(), 7);
+    }
+
+    #[test]
+    fn drops_groups_that_left_the_window() {
+        let config = FecConfig { group_size: 2, window: 1 };
+        let mut encoder = FecEncoder::new(&config);
+        let mut wire = Vec::new();
+        let sent = datagrams(4);
+        sent.iter().for_each(|d| encoder.encode(d, &mut wire));
+
+        // A straggler of the first group neither comes back nor pushes out the second
+        let mut decoder = FecDecoder::new(&config);
+        let mut delivered = Vec::new();
+        for i in [0, 3, 1, 5] {
+            decoder.decode(wire[i].clone(), &mut delivered).unwrap();
+        }
+        assert_eq!(delivered, [sent[0].clone(), sent[2].clone(), sent[3].clone()]);
+        assert_eq!(decoder.stats(), FecStats { recovered: 1, unrecoverable: 0 });
+    }
+
+    #[test]
+    fn recovers_after_late_members() {
+        let config = FecConfig { group_size: 3, window: 8 };
+        let mut encoder = FecEncoder::new(&config);
+        let mut wire = Vec::new();
+        let sent = datagrams(3);
+        sent.iter().for_each(|d| encoder.encode(d, &mut wire));
+
+        // Member 1 is lost and member 2 overtaken by the parity
+        let mut decoder = FecDecoder::new(&config);
+        let mut delivered = Vec::new();
+        for i in [0, 3, 2] {
+            decoder.decode(wire[i].clone(), &mut delivered).unwrap();
+        }
+        assert_eq!(delivered, [sent[0].clone(), sent[2].clone(), sent[1].clone()]);
+        assert_eq!(decoder.stats(), FecStats { recovered: 1, unrecoverable: 0 });
+    }
+
+    #[test]
+    fn ignores_duplicates_and_late_arrivals() {
+        let config = FecConfig { group_size: 2, window: 8 };
+        let mut encoder = FecEncoder::new(&config);
+        let mut wire = Vec::new();
+        datagrams(2).iter().for_each(|d| encoder.encode(d, &mut wire));
+
+        let mut decoder = FecDecoder::new(&config);
+        let mut delivered = Vec::new();
+        for i in [0, 0, 2, 2] {
+            decoder.decode(wire[i].clone(), &mut delivered).unwrap();
+        }
+        assert_eq!(delivered.len(), 2);
+        // The recovered datagram arriving late is not delivered twice
+        decoder.decode(wire[1].clone(), &mut delivered).unwrap();
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(decoder.stats().recovered, 1);
+    }
+}
@@ -0,0 +1,518 @@
+//! Opt-in fragmentation of frames too large for a single datagram.
+//!
+//! A fragmented frame is sent as `count` datagrams, each carrying
+//! `FRAG_MAGIC(4) | SEQ(4, BE) | INDEX(2, BE) | COUNT(2, BE) | CHUNK`, where the chunks
+//! concatenate to the frame's regular nwd1 encoding. Frames that fit are sent unchanged, so a
+//! [`DatagramReceiver`] also accepts datagrams from [`send_frame_datagram`](crate::send_frame_datagram).
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use nwd1::Frame;
+use quinn::Connection;
+
+use crate::datagram::{decode_datagram, send_error};
+use crate::fec::{FEC_OVERHEAD, FecConfig, FecDecoder, FecEncoder, FecStats};
+use crate::{
+    FIXED_LEN, FrameLimits, HEADER_LEN, MAX_FRAME_LEN, Nwd1QuicError, check_prefix, encode_into,
+};
+
+/// Magic of a fragment datagram, distinct from the nwd1 `MAGIC` of a whole frame.
+pub const FRAG_MAGIC: &[u8; 4] = b"NWDF";
+
+const FRAG_HEADER_LEN: usize = 4 + 4 + 2 + 2; // FRAG_MAGIC + SEQ + INDEX + COUNT
+const SLOT_LEN: usize = std::mem::size_of::<Option<Bytes>>(); // reassembly cost per announced fragment
+
+/// Receive-side bounds on fragment reassembly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentConfig {
+    /// Largest number of bytes held by incomplete frames, including a slot per announced
+    /// fragment; the oldest are dropped to stay below it. Defaults to 8 MiB.
+    pub max_reassembly_bytes: usize,
+    /// How long an incomplete frame waits for its missing fragments. Defaults to 3 seconds.
+    pub timeout: Duration,
+}
+
+impl Default for FragmentConfig {
+    fn default() -> Self {
+        Self { max_reassembly_bytes: MAX_FRAME_LEN, timeout: Duration::from_secs(3) }
+    }
+}
+
+/// Sends frames as datagrams, fragmenting those larger than
+/// [`Connection::max_datagram_size`].
+#[derive(Debug)]
+pub struct DatagramSender {
+    conn: Connection,
+    next_seq: u32,
+    fec: Option<FecEncoder>,
+}
+
+impl DatagramSender {
+    /// Send datagrams over `conn`.
+    pub fn new(conn: Connection) -> Self {
+        Self { conn, next_seq: 0, fec: None }
+    }
+
+    /// Protect every datagram sent from now on with XOR parity; see [`FecEncoder`].
+    ///
+    /// The receiving [`DatagramReceiver`] must enable it as well.
+    pub fn set_fec(&mut self, config: FecConfig) {
+        self.fec = Some(FecEncoder::new(&config));
+    }
+
+    /// Send `frame`, as a single datagram when it fits and as numbered fragments otherwise.
+    ///
+    /// Fragments wait for send buffer space rather than displacing queued datagrams, so a large
+    /// frame does not evict its own fragments. Losing any fragment loses the whole frame.
+    pub async fn send(&mut self, frame: &Frame) -> Result<(), Nwd1QuicError> {
+        let len = FIXED_LEN + frame.payload.len();
+        let mut max = self.conn.max_datagram_size().ok_or(Nwd1QuicError::DatagramsUnsupported)?;
+        if self.fec.is_some() {
+            max = max.saturating_sub(FEC_OVERHEAD);
+        }
+
+        let mut encoded = BytesMut::with_capacity(len);
+        encode_into(frame, &mut encoded);
+        if len <= max {
+            return self.transmit(encoded.freeze(), false).await;
+        }
+
+        let chunk_len = max.saturating_sub(FRAG_HEADER_LEN);
+        let count = if chunk_len == 0 { usize::MAX } else { len.div_ceil(chunk_len) };
+        let Ok(count) = u16::try_from(count) else {
+            return Err(Nwd1QuicError::DatagramTooLarge {
+                len,
+                max: chunk_len * u16::MAX as usize,
+            });
+        };
+
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        for (index, chunk) in encoded.chunks(chunk_len).enumerate() {
+            let mut datagram = BytesMut::with_capacity(FRAG_HEADER_LEN + chunk.len());
+            datagram.put_slice(FRAG_MAGIC);
+            datagram.put_u32(seq);
+            datagram.put_u16(index as u16);
+            datagram.put_u16(count);
+            datagram.put_slice(chunk);
+            self.transmit(datagram.freeze(), true).await?;
+        }
+        Ok(())
+    }
+
+    /// Send the parity of a partially filled FEC group, so its datagrams can be recovered
+    /// without waiting for more traffic. Does nothing without FEC.
+    pub async fn flush(&mut self) -> Result<(), Nwd1QuicError> {
+        let Some(fec) = &mut self.fec else { return Ok(()) };
+        let mut out = Vec::with_capacity(1);
+        fec.flush(&mut out);
+        for datagram in out {
+            put_datagram(&self.conn, datagram, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Send one datagram, through the FEC encoder if enabled.
+    async fn transmit(&mut self, datagram: Bytes, wait: bool) -> Result<(), Nwd1QuicError> {
+        let Some(fec) = &mut self.fec else {
+            return put_datagram(&self.conn, datagram, wait).await;
+        };
+        let mut out = Vec::with_capacity(2);
+        fec.encode(&datagram, &mut out);
+        for datagram in out {
+            put_datagram(&self.conn, datagram, wait).await?;
+        }
+        Ok(())
+    }
+
+    /// The underlying connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+/// Hand `datagram` to quinn, waiting for buffer space if `wait` is set instead of displacing
+/// older queued datagrams.
+async fn put_datagram(conn: &Connection, datagram: Bytes, wait: bool) -> Result<(), Nwd1QuicError> {
+    let len = datagram.len();
+    let result =
+        if wait { conn.send_datagram_wait(datagram).await } else { conn.send_datagram(datagram) };
+    result.map_err(|e| send_error(conn, len, e))
+}
+
+/// Receives datagram frames, reassembling those sent as fragments by [`DatagramSender`].
+///
+/// Incomplete frames are dropped once they exceed [`FragmentConfig::timeout`] or to keep the
+/// reassembly buffer within [`FragmentConfig::max_reassembly_bytes`]; both are checked as
+/// datagrams arrive. Use a single receiver per connection, since each datagram is delivered
+/// to only one reader.
+#[derive(Debug)]
+pub struct DatagramReceiver {
+    conn: Connection,
+    limits: FrameLimits,
+    reassembler: Reassembler,
+    fec: Option<FecDecoder>,
+    /// Datagrams unwrapped or rebuilt by the FEC decoder and not processed yet.
+    decoded: Vec<Bytes>,
+}
+
+impl DatagramReceiver {
+    /// Receive datagrams from `conn`, reassembling fragments within `config`.
+    pub fn new(conn: Connection, config: FragmentConfig) -> Self {
+        Self {
+            conn,
+            limits: FrameLimits::default(),
+            reassembler: Reassembler::new(config),
+            fec: None,
+            decoded: Vec::new(),
+        }
+    }
+
+    /// Replace the limits applied to received frames.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.limits = limits;
+    }
+
+    /// Expect datagrams protected by a [`DatagramSender`] with the same FEC settings.
+    pub fn set_fec(&mut self, config: FecConfig) {
+        self.fec = Some(FecDecoder::new(&config));
+    }
+
+    /// Receive the next complete frame.
+    ///
+    /// A malformed datagram or fragment is reported as a protocol error; receiving can continue
+    /// with the next call.
+    pub async fn recv(&mut self) -> Result<Frame, Nwd1QuicError> {
+        loop {
+            let datagram = match self.decoded.pop() {
+                Some(datagram) => datagram,
+                None => {
+                    let datagram = self.conn.read_datagram().await?;
+                    match &mut self.fec {
+                        Some(fec) => {
+                            fec.decode(datagram, &mut self.decoded)?;
+                            continue;
+                        }
+                        None => datagram,
+                    }
+                }
+            };
+            if !datagram.starts_with(FRAG_MAGIC) {
+                return decode_datagram(datagram, &self.limits);
+            }
+
+            let fragment = Fragment::parse(datagram)?;
+            if !fragment.fits(HEADER_LEN + self.limits.largest_len()) {
+                self.reassembler.discard(fragment.seq);
+                return Err(Nwd1QuicError::BadFragment {
+                    index: fragment.index,
+                    count: fragment.count,
+                });
+            }
+            // The first fragment carries the fixed prefix, so a frame over the limits is
+            // refused before the rest of it is buffered
+            if fragment.index == 0
+                && let Err(e) = check_prefix(&fragment.chunk, &self.limits)
+            {
+                self.reassembler.discard(fragment.seq);
+                return Err(e);
+            }
+            if let Some(frame) = self.reassembler.push(Instant::now(), fragment)? {
+                return decode_datagram(frame, &self.limits);
+            }
+        }
+    }
+
+    /// Number of incomplete frames dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.reassembler.dropped
+    }
+
+    /// Forward error correction counters, if FEC is enabled.
+    pub fn fec_stats(&self) -> Option<FecStats> {
+        self.fec.as_ref().map(FecDecoder::stats)
+    }
+
+    /// The underlying connection.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+/// A parsed fragment datagram.
+#[derive(Debug)]
+pub(crate) struct Fragment {
+    seq: u32,
+    index: u16,
+    count: u16,
+    chunk: Bytes,
+}
+
+impl Fragment {
+    fn parse(mut datagram: Bytes) -> Result<Self, Nwd1QuicError> {
+        if datagram.len() < FRAG_HEADER_LEN {
+            return Err(Nwd1QuicError::Truncated {
+                expected: FRAG_HEADER_LEN,
+                got: datagram.len(),
+            });
+        }
+        let magic = datagram.split_to(4);
+        if magic != FRAG_MAGIC[..] {
+            return Err(Nwd1QuicError::BadMagic([magic[0], magic[1], magic[2], magic[3]]));
+        }
+        let (seq, index, count) = (datagram.get_u32(), datagram.get_u16(), datagram.get_u16());
+        if index >= count || datagram.is_empty() {
+            return Err(Nwd1QuicError::BadFragment { index, count });
+        }
+        Ok(Self { seq, index, count, chunk: datagram })
+    }
+
+    /// Whether the frame may be at most `max_len` bytes long.
+    ///
+    /// Every fragment but the last carries a full chunk, so `count` of them make a frame longer
+    /// than `count - 1` chunks.
+    fn fits(&self, max_len: usize) -> bool {
+        self.index + 1 == self.count || (self.count as usize - 1) * self.chunk.len() < max_len
+    }
+}
+
+/// Sans-IO reassembly of fragments into encoded frames.
+#[derive(Debug)]
+pub(crate) struct Reassembler {
+    config: FragmentConfig,
+    partial: HashMap<u32, Partial>,
+    buffered: usize,
+    dropped: u64,
+}
+
+#[derive(Debug)]
+struct Partial {
+    started: Instant,
+    chunks: Vec<Option<Bytes>>,
+    missing: u16,
+    bytes: usize,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: FragmentConfig) -> Self {
+        Self { config, partial: HashMap::new(), buffered: 0, dropped: 0 }
+    }
+
+    /// Add a fragment received at `now`, returning the encoded frame once it is complete.
+    pub(crate) fn push(
+        &mut self,
+        now: Instant,
+        fragment: Fragment,
+    ) -> Result<Option<Bytes>, Nwd1QuicError> {
+        self.expire(now);
+
+        let Fragment { seq, index, count, chunk } = fragment;
+        if let Some(partial) = self.partial.get(&seq)
+            && partial.chunks.len() != count as usize
+        {
+            self.discard(seq);
+            return Err(Nwd1QuicError::BadFragment { index, count });
+        }
+        // A new frame is charged for its slot table before it is allocated
+        let table = count as usize * SLOT_LEN;
+        if chunk.len() + table > self.config.max_reassembly_bytes {
+            self.discard(seq);
+            self.dropped += 1;
+            return Ok(None);
+        }
+        let cost = |r: &Self| chunk.len() + if r.partial.contains_key(&seq) { 0 } else { table };
+        while self.buffered + cost(self) > self.config.max_reassembly_bytes {
+            self.evict_oldest(seq);
+        }
+
+        if !self.partial.contains_key(&seq) {
+            self.buffered += table;
+        }
+        let partial = self.partial.entry(seq).or_insert_with(|| Partial {
+            started: now,
+            chunks: vec![None; count as usize],
+            missing: count,
+            bytes: table,
+        });
+        let slot = &mut partial.chunks[index as usize];
+        if slot.is_some() {
+            return Ok(None);
+        }
+        partial.missing -= 1;
+        partial.bytes += chunk.len();
+        self.buffered += chunk.len();
+        *slot = Some(chunk);
+        if partial.missing > 0 {
+            return Ok(None);
+        }
+
+        let partial = self.partial.remove(&seq).expect("partial present");
+        self.buffered -= partial.bytes;
+        let mut frame = BytesMut::with_capacity(partial.bytes - table);
+        partial.chunks.into_iter().flatten().for_each(|chunk| frame.extend_from_slice(&chunk));
+        Ok(Some(frame.freeze()))
+    }
+
+    /// Forget the fragments of `seq` received so far.
+    pub(crate) fn discard(&mut self, seq: u32) {
+        if let Some(partial) = self.partial.remove(&seq) {
+            self.buffered -= partial.bytes;
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let timeout = self.config.timeout;
+        let (mut frames, mut bytes) = (0, 0);
+        self.partial.retain(|_, partial| {
+            let keep = now.saturating_duration_since(partial.started) < timeout;
+            if !keep {
+                frames += 1;
+                bytes += partial.bytes;
+            }
+            keep
+        });
+        self.dropped += frames;
+        self.buffered -= bytes;
+    }
+
+    /// Drop the oldest incomplete frame, preferring any frame other than `keep`.
+    fn evict_oldest(&mut self, keep: u32) {
+        let oldest = self
+            .partial
+            .iter()
+            .min_by_key(|&(&seq, partial)| (seq == keep, partial.started))
+            .map(|(&seq, _)| seq);
+        if let Some(seq) = oldest {
+            self.discard(seq);
+            self.dropped += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, loopback};
+
+    fn fragment(seq: u32, index: u16, count: u16, chunk: &'static [u8]) -> Fragment {
+        Fragment { seq, index, count, chunk: Bytes::from_static(chunk) }
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let mut r = Reassembler::new(FragmentConfig::default());
+        let now = Instant::now();
+
+        assert!(r.push(now, fragment(1, 2, 3, b"ef")).unwrap().is_none());
+        assert!(r.push(now, fragment(1, 0, 3, b"ab")).unwrap().is_none());
+        // Duplicates are ignored
+        assert!(r.push(now, fragment(1, 0, 3, b"ab")).unwrap().is_none());
+        assert_eq!(r.push(now, fragment(1, 1, 3, b"cd")).unwrap().unwrap(), "abcdef");
+        assert_eq!((r.buffered, r.dropped), (0, 0));
+
+        assert!(r.push(now, fragment(2, 0, 2, b"ab")).unwrap().is_none());
+        let Err(err) = r.push(now, fragment(2, 1, 3, b"cd")) else { panic!() };
+        assert!(matches!(err, Nwd1QuicError::BadFragment { index: 1, count: 3 }));
+        assert!(r.partial.is_empty());
+    }
+
+    #[test]
+    fn drops_expired_and_evicted_frames() {
+        // Room for two frames of two fragments, and six bytes of their chunks
+        let table = 2 * SLOT_LEN;
+        let config =
+            FragmentConfig { max_reassembly_bytes: 2 * table + 6, timeout: Duration::from_secs(1) };
+        let mut r = Reassembler::new(config);
+        let start = Instant::now();
+
+        r.push(start, fragment(1, 0, 2, b"aa")).unwrap();
+        r.push(start + Duration::from_secs(2), fragment(2, 0, 2, b"bb")).unwrap();
+        assert_eq!((r.buffered, r.dropped), (table + 2, 1));
+
+        // Over budget: the oldest incomplete frame makes room
+        let later = start + Duration::from_millis(2500);
+        r.push(later, fragment(3, 0, 2, b"ccc")).unwrap();
+        r.push(later, fragment(3, 1, 2, b"d")).unwrap();
+        r.push(later, fragment(4, 0, 2, b"eeeee")).unwrap();
+        assert!(!r.partial.contains_key(&2));
+        assert_eq!((r.buffered, r.dropped), (table + 5, 2));
+
+        // A frame whose slot table alone is larger than the buffer is dropped outright
+        assert!(r.push(later, fragment(5, 0, u16::MAX, b"f")).unwrap().is_none());
+        assert_eq!((r.buffered, r.dropped), (table + 5, 3));
+    }
+
+    #[test]
+    fn bounds_large_fragment_counts() {
+        let config = FragmentConfig::default();
+        let mut r = Reassembler::new(config.clone());
+        let now = Instant::now();
+
+        // Every frame announces the most fragments possible but sends a byte of one
+        for seq in 0..1000 {
+            assert!(r.push(now, fragment(seq, 1, u16::MAX, b"x")).unwrap().is_none());
+            assert!(r.buffered <= config.max_reassembly_bytes);
+        }
+        let tables = config.max_reassembly_bytes / (u16::MAX as usize * SLOT_LEN + 1);
+        assert_eq!((r.partial.len(), r.dropped), (tables, 1000 - tables as u64));
+
+        // Non-final fragments must be full chunks of a frame within the limits
+        let max_len = HEADER_LEN + FrameLimits::default().largest_len();
+        assert!(fragment(1, 1, u16::MAX, b"x").fits(max_len));
+        assert!(!fragment(1, 1, u16::MAX, &[0; 200]).fits(max_len));
+        assert!(fragment(1, u16::MAX - 1, u16::MAX, &[0; 200]).fits(max_len));
+
+        let empty = Bytes::from_static(b"NWDF\0\0\0\x01\0\0\xff\xff");
+        let Err(err) = Fragment::parse(empty) else { panic!("empty fragment accepted") };
+        assert!(matches!(err, Nwd1QuicError::BadFragment { index: 0, count: u16::MAX }));
+    }
+
+    #[test]
+    fn rejects_malformed_fragments() {
+        let Err(err) = Fragment::parse(Bytes::from_static(b"NWDF\0\0")) else { panic!() };
+        assert!(matches!(err, Nwd1QuicError::Truncated { expected: 12, got: 6 }));
+
+        let bad = Bytes::from_static(b"NWDF\0\0\0\x01\0\x02\0\x02xy");
+        let Err(err) = Fragment::parse(bad) else { panic!() };
+        assert!(matches!(err, Nwd1QuicError::BadFragment { index: 2, count: 2 }));
+    }
+
+    #[tokio::test]
+    async fn fragments_oversize_frames() {
+        let lb = loopback().await;
+        let max = lb.client.max_datagram_size().unwrap();
+        let mut sender = DatagramSender::new(lb.client.clone());
+        let mut receiver = DatagramReceiver::new(lb.server.clone(), FragmentConfig::default());
+
+        let payload: Vec<u8> = (0..3 * max).map(|i| i as u8).collect();
+        for payload in [Bytes::from(payload), Bytes::from_static(b"small")] {
+            let frame = frame(9, 4, payload);
+            sender.send(&frame).await.unwrap();
+
+            let received = receiver.recv().await.unwrap();
+            assert_eq!(received.kind, 4);
+            assert_eq!(received.payload, frame.payload);
+        }
+        assert_eq!(receiver.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn carries_fragments_under_fec() {
+        let lb = loopback().await;
+        let max = lb.client.max_datagram_size().unwrap();
+        let mut sender = DatagramSender::new(lb.client.clone());
+        let mut receiver = DatagramReceiver::new(lb.server.clone(), FragmentConfig::default());
+        sender.set_fec(FecConfig::default());
+        receiver.set_fec(FecConfig::default());
+
+        // Exactly the datagram size: must be fragmented to leave room for the FEC header
+        let frame = frame(9, 4, vec![7; max - FIXED_LEN]);
+        sender.send(&frame).await.unwrap();
+        sender.flush().await.unwrap();
+
+        assert_eq!(receiver.recv().await.unwrap().payload, frame.payload);
+        assert_eq!(receiver.fec_stats(), Some(FecStats::default()));
+    }
+}
@@ -0,0 +1,236 @@
+//! Metadata headers carried in an envelope at the start of the payload.
+//!
+//! [`HEADERS_FLAG`] set in `VER` announces the envelope, placed after the
+//! [deadline](crate::DEADLINE_FLAG) prefix if there is one:
+//!
+//! ```text
+//! VERSION (1B) | COUNT (2B) | { NAME_LEN (1B) | NAME | VALUE_LEN (4B) | VALUE } * COUNT
+//! ```
+//!
+//! Frames without headers carry no envelope and are never parsed for one.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use nwd1::Frame;
+
+use crate::{DEADLINE_FLAG, Nwd1QuicError};
+
+/// `VER` bit announcing a header envelope in the payload.
+pub const HEADERS_FLAG: u64 = 1 << 63;
+
+/// Envelope encoding written by [`set_headers`]; other versions are rejected.
+pub const HEADERS_VERSION: u8 = 1;
+
+/// An ordered map of header names to binary values.
+///
+/// Names are case-sensitive UTF-8, lowercase by convention (`content-type`, `tenant`,
+/// `traceparent`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, Bytes)>,
+}
+
+impl Headers {
+    /// An empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set `name` to `value`, replacing a previous value.
+    ///
+    /// # Panics
+    ///
+    /// If `name` is longer than 255 bytes, or the map already holds 65535 headers.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<Bytes>) {
+        let (name, value) = (name.into(), value.into());
+        assert!(name.len() <= u8::MAX as usize, "header name longer than 255 bytes");
+        match self.entries.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, v)) => *v = value,
+            None => {
+                assert!(self.entries.len() < u16::MAX as usize, "too many headers");
+                self.entries.push((name, value));
+            }
+        }
+    }
+
+    /// The value of `name`.
+    pub fn get(&self, name: &str) -> Option<&Bytes> {
+        self.entries.iter().find(|(n, _)| n == name).map(|(_, v)| v)
+    }
+
+    /// The value of `name`, if it is UTF-8.
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| std::str::from_utf8(v).ok())
+    }
+
+    /// Remove `name`, returning its value.
+    pub fn remove(&mut self, name: &str) -> Option<Bytes> {
+        let at = self.entries.iter().position(|(n, _)| n == name)?;
+        Some(self.entries.remove(at).1)
+    }
+
+    /// Names and values, in insertion order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Bytes)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v))
+    }
+
+    /// Number of headers.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether there are no headers.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn encoded_len(&self) -> usize {
+        3 + self.entries.iter().map(|(n, v)| 1 + n.len() + 4 + v.len()).sum::<usize>()
+    }
+
+    fn encode(&self, dst: &mut BytesMut) {
+        dst.put_u8(HEADERS_VERSION);
+        dst.put_u16(self.entries.len() as u16);
+        for (name, value) in &self.entries {
+            dst.put_u8(name.len() as u8);
+            dst.put_slice(name.as_bytes());
+            dst.put_u32(value.len() as u32);
+            dst.put_slice(value);
+        }
+    }
+
+    /// Decode an envelope from the start of `src`, returning it and its length.
+    fn decode(src: &Bytes) -> Option<(Self, usize)> {
+        let mut at = 0usize;
+        let mut next = |len: usize| {
+            let field = src.get(at..at.checked_add(len)?)?;
+            at += len;
+            Some(field)
+        };
+        if next(1)?[0] != HEADERS_VERSION {
+            return None;
+        }
+        let count = u16::from_be_bytes(next(2)?.try_into().ok()?);
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let len = next(1)?[0].into();
+            let name = std::str::from_utf8(next(len)?).ok()?.to_owned();
+            let len = u32::from_be_bytes(next(4)?.try_into().ok()?) as usize;
+            entries.push((name, src.slice_ref(next(len)?)));
+        }
+        Some((Self { entries }, at))
+    }
+}
+
+/// Attach `headers` to `frame`, replacing any it already carries; empty `headers` remove the
+/// envelope. Copies the payload.
+///
+/// Fails with [`Nwd1QuicError::BadExtension`] if the frame's current extensions are malformed.
+pub fn set_headers(frame: &mut Frame, headers: &Headers) -> Result<(), Nwd1QuicError> {
+    take_headers(frame)?;
+    if headers.is_empty() {
+        return Ok(());
+    }
+    let at = prefix_len(frame);
+    if frame.payload.len() < at {
+        return Err(Nwd1QuicError::BadExtension { flag: DEADLINE_FLAG });
+    }
+    let mut payload = BytesMut::with_capacity(frame.payload.len() + headers.encoded_len());
+    payload.put_slice(&frame.payload[..at]);
+    headers.encode(&mut payload);
+    payload.put_slice(&frame.payload[at..]);
+    frame.payload = payload.freeze();
+    frame.ver |= HEADERS_FLAG;
+    Ok(())
+}
+
+/// Remove the headers `frame` carries, restoring its original payload and `VER`.
+///
+/// Returns empty headers if the frame has none, and [`Nwd1QuicError::BadExtension`] if the
+/// envelope is malformed or of an unknown version.
+pub fn take_headers(frame: &mut Frame) -> Result<Headers, Nwd1QuicError> {
+    if frame.ver & HEADERS_FLAG == 0 {
+        return Ok(Headers::new());
+    }
+    let at = prefix_len(frame);
+    let decoded = frame.payload.get(at..).and_then(|_| Headers::decode(&frame.payload.slice(at..)));
+    let Some((headers, len)) = decoded else {
+        return Err(Nwd1QuicError::BadExtension { flag: HEADERS_FLAG });
+    };
+    frame.payload = if at == 0 {
+        frame.payload.slice(len..)
+    } else {
+        let mut payload = BytesMut::with_capacity(frame.payload.len() - len);
+        payload.put_slice(&frame.payload[..at]);
+        payload.put_slice(&frame.payload[at + len..]);
+        payload.freeze()
+    };
+    frame.ver &= !HEADERS_FLAG;
+    Ok(headers)
+}
+
+/// Length of the extensions preceding the header envelope.
+fn prefix_len(frame: &Frame) -> usize {
+    if frame.ver & DEADLINE_FLAG != 0 { crate::deadline::DEADLINE_LEN } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, loopback};
+    use crate::{FrameReceiver, FrameSender, set_deadline, take_deadline};
+
+    fn headers() -> Headers {
+        let mut headers = Headers::new();
+        headers.insert("content-type", "application/json");
+        headers.insert("tenant", Bytes::from_static(b"\x00\x01"));
+        headers
+    }
+
+    #[test]
+    fn roundtrip_after_deadline() {
+        let mut f = frame(1, 1, "body");
+        set_headers(&mut f, &Headers::new()).unwrap();
+        assert_eq!((f.ver, f.payload.len()), (1, 4));
+
+        set_deadline(&mut f, std::time::Duration::from_millis(50));
+        set_headers(&mut f, &headers()).unwrap();
+        assert_eq!(f.ver, 1 | DEADLINE_FLAG | HEADERS_FLAG);
+
+        let taken = take_headers(&mut f).unwrap();
+        assert_eq!(taken.get_str("content-type"), Some("application/json"));
+        assert_eq!(taken.iter().map(|(n, _)| n).collect::<Vec<_>>(), ["content-type", "tenant"]);
+        assert!(take_deadline(&mut f).unwrap().is_some());
+        assert_eq!((f.ver, &f.payload[..]), (1, &b"body"[..]));
+    }
+
+    #[test]
+    fn rejects_malformed_envelopes() {
+        let mut f = frame(1, 1, "body");
+        set_headers(&mut f, &headers()).unwrap();
+
+        let mut truncated = Frame { payload: f.payload.slice(..10), ..frame(1, 1, "body") };
+        truncated.ver |= HEADERS_FLAG;
+        assert!(matches!(take_headers(&mut truncated), Err(Nwd1QuicError::BadExtension { .. })));
+
+        let mut payload = BytesMut::from(&f.payload[..]);
+        payload[0] = HEADERS_VERSION + 1;
+        let mut unknown = Frame { payload: payload.freeze(), ver: f.ver, ..frame(1, 1, "body") };
+        assert!(take_headers(&mut unknown).is_err());
+    }
+
+    #[tokio::test]
+    async fn send_and_recv_with_headers() {
+        let pair = loopback().await;
+        let (send, _) = pair.client.open_bi().await.unwrap();
+        let mut tx = FrameSender::new(send);
+        tx.send_with_headers(&frame(1, 1, "body"), &headers()).await.unwrap();
+        tx.send(&frame(1, 1, "body")).await.unwrap();
+
+        let (_, recv) = pair.server.accept_bi().await.unwrap();
+        let mut rx = FrameReceiver::new(recv);
+        let (f, received) = rx.recv_with_headers().await.unwrap().unwrap();
+        assert_eq!((f.ver, &f.payload[..], received), (1, &b"body"[..], headers()));
+        let (_, received) = rx.recv_with_headers().await.unwrap().unwrap();
+        assert!(received.is_empty());
+    }
+}
@@ -0,0 +1,183 @@
+//! Hooks applied to every frame sent or received through the high-level handles.
+
+use std::fmt;
+use std::sync::Arc;
+
+use nwd1::Frame;
+
+use crate::Nwd1QuicError;
+
+/// What happens to a frame after an [`Interceptor`] has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intercept {
+    /// Hand the (possibly modified) frame to the next interceptor, then on.
+    Pass,
+    /// Discard the frame silently.
+    Drop,
+}
+
+/// Cross-cutting behaviour around frame I/O: logging, metrics, auth checks, payload transforms.
+///
+/// Hooks may modify the frame in place, drop it, or reject it with an error, which fails the
+/// send or receive it was called for. Both hooks pass every frame by default.
+pub trait Interceptor: Send + Sync + 'static {
+    /// Called before a frame is written.
+    fn on_send(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+        let _ = frame;
+        Ok(Intercept::Pass)
+    }
+
+    /// Called after a frame is read, before it reaches the application.
+    fn on_recv(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+        let _ = frame;
+        Ok(Intercept::Pass)
+    }
+}
+
+/// An ordered stack of [`Interceptor`]s.
+///
+/// Outgoing frames pass the interceptors in the order they were added, incoming frames in
+/// reverse, so the first interceptor is the outermost layer on both paths. Cloning is cheap.
+///
+/// Applied by [`FrameSender`](crate::FrameSender), [`FrameReceiver`](crate::FrameReceiver)
+/// and [`Nwd1Server`](crate::Nwd1Server) once set on them, and to the calls opened with
+/// [`call_streaming_with`](crate::call_streaming_with) or
+/// [`call_duplex_with`](crate::call_duplex_with).
+#[derive(Clone, Default)]
+pub struct InterceptorStack {
+    layers: Vec<Arc<dyn Interceptor>>,
+}
+
+impl InterceptorStack {
+    /// An empty stack, passing every frame unchanged.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `interceptor` as the innermost layer.
+    pub fn with(mut self, interceptor: impl Interceptor) -> Self {
+        self.layers.push(Arc::new(interceptor));
+        self
+    }
+
+    /// Whether the stack has no interceptors.
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Run the send hooks, returning the frame to write or `None` if it was dropped.
+    pub fn on_send(&self, mut frame: Frame) -> Result<Option<Frame>, Nwd1QuicError> {
+        for layer in &self.layers {
+            if layer.on_send(&mut frame)? == Intercept::Drop {
+                return Ok(None);
+            }
+        }
+        Ok(Some(frame))
+    }
+
+    /// Run the receive hooks, returning the frame to deliver or `None` if it was dropped.
+    pub fn on_recv(&self, mut frame: Frame) -> Result<Option<Frame>, Nwd1QuicError> {
+        for layer in self.layers.iter().rev() {
+            if layer.on_recv(&mut frame)? == Intercept::Drop {
+                return Ok(None);
+            }
+        }
+        Ok(Some(frame))
+    }
+}
+
+impl fmt::Debug for InterceptorStack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InterceptorStack").field("layers", &self.layers.len()).finish()
+    }
+}
+
+/// Copy a frame; the payload is shared, not copied.
+pub(crate) fn clone_frame(frame: &Frame) -> Frame {
+    Frame { id: frame.id, kind: frame.kind, ver: frame.ver, payload: frame.payload.clone() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::test_util::{frame, loopback};
+    use crate::{FrameReceiver, FrameSender};
+
+    /// Records the order hooks run in and appends its tag to payloads on send.
+    struct Tag(&'static str, Arc<Mutex<Vec<String>>>);
+
+    impl Interceptor for Tag {
+        fn on_send(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+            self.1.lock().unwrap().push(format!("send {}", self.0));
+            frame.payload = [&frame.payload[..], self.0.as_bytes()].concat().into();
+            Ok(Intercept::Pass)
+        }
+
+        fn on_recv(&self, _frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+            self.1.lock().unwrap().push(format!("recv {}", self.0));
+            Ok(Intercept::Pass)
+        }
+    }
+
+    /// Drops kind 0 and rejects kind 255 in both directions.
+    struct Gate;
+
+    impl Gate {
+        fn check(frame: &Frame) -> Result<Intercept, Nwd1QuicError> {
+            match frame.kind {
+                0 => Ok(Intercept::Drop),
+                255 => Err(Nwd1QuicError::Rejected("kind 255 is reserved".into())),
+                _ => Ok(Intercept::Pass),
+            }
+        }
+    }
+
+    impl Interceptor for Gate {
+        fn on_send(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+            Self::check(frame)
+        }
+
+        fn on_recv(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+            Self::check(frame)
+        }
+    }
+
+    #[test]
+    fn stack_runs_layers_as_an_onion() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let stack = InterceptorStack::new().with(Tag("a", log.clone())).with(Tag("b", log.clone()));
+
+        let sent = stack.on_send(frame(1, 1, ">")).unwrap().unwrap();
+        assert_eq!(sent.payload, ">ab");
+        stack.on_recv(sent).unwrap().unwrap();
+        assert_eq!(*log.lock().unwrap(), ["send a", "send b", "recv b", "recv a"]);
+
+        let gated = InterceptorStack::new().with(Gate).with(Tag("c", log.clone()));
+        assert!(gated.on_send(frame(1, 0, ">")).unwrap().is_none());
+        assert!(matches!(gated.on_send(frame(1, 255, ">")), Err(Nwd1QuicError::Rejected(_))));
+        assert_eq!(log.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn applies_to_channel_handles() {
+        let lb = loopback().await;
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut tx = FrameSender::new(lb.client.open_uni().await.unwrap());
+        tx.set_interceptors(InterceptorStack::new().with(Tag("x", log.clone())));
+
+        for kind in [0, 1, 255] {
+            tx.send(&frame(1, kind, ">")).await.unwrap();
+        }
+        tx.finish().unwrap();
+
+        // Kind 0 is dropped on receive, kind 255 rejected without ending the stream
+        let mut rx = FrameReceiver::new(lb.server.accept_uni().await.unwrap());
+        rx.set_interceptors(InterceptorStack::new().with(Gate));
+        let received = rx.recv().await.unwrap().unwrap();
+        assert_eq!((received.kind, &received.payload[..]), (1, &b">x"[..]));
+        assert!(matches!(rx.recv().await, Err(Nwd1QuicError::Rejected(_))));
+        assert!(rx.recv().await.unwrap().is_none());
+    }
+}
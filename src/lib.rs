@@ -1,97 +1,417 @@
 //! nwd1-quic
 //! QUIC transport for `nwd1` binary frames.
 //!
-//! NOTE: future optimization idea — replace `Vec<u8>` allocations with pooled or preallocated `BytesMut` buffers to reduce churn under high stream load.
+//! Under high stream load, a shared [`BufferPool`] lets [`FrameReader`] reuse `BytesMut`
+//! buffers instead of allocating per frame, and [`send_frame_with_pool`] /
+//! [`send_frames_with_pool`] encode headers into them; sends never copy the payload.
 //!
 //! This crate integrates [`nwd1::Frame`] with the [`quinn`] QUIC implementation,
-//! providing async send/receive helpers for bidirectional streams.
+//! providing async send/receive helpers for bidirectional streams, plus
+//! [`send_frame_datagram`] / [`recv_frame_datagram`] for loss-tolerant frames.
 
-use bytes::BytesMut;
-use nwd1::{Frame, MAGIC, decode, encode};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use netid64::NetId64;
+use nwd1::{Frame, MAGIC};
 use quinn::{RecvStream, SendStream};
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
+
+use crate::pool::PooledBuf;
+
+mod call;
+mod client;
+mod codec;
+mod datagram;
+mod deadline;
+mod error;
+mod fec;
+mod fragment;
+mod headers;
+mod interceptor;
+mod limits;
+mod pool;
+mod reader;
+mod router;
+mod rpc;
+mod server;
+mod service;
+mod stream;
+#[cfg(test)]
+mod test_util;
+mod trace;
+mod uni;
+
+pub use call::{
+    CallReceiver, CallSender, KIND_CANCEL, KIND_END, KIND_ERROR, call_duplex, call_duplex_with,
+    call_streaming, call_streaming_with, is_terminal_kind,
+};
+pub use client::{FrameReceiver, FrameSender, Nwd1Client, default_transport_config};
+pub use codec::Nwd1Codec;
+pub use datagram::{recv_frame_datagram, recv_frame_datagram_with_limits, send_frame_datagram};
+pub use deadline::{DEADLINE_FLAG, current_deadline, set_deadline, take_deadline, with_deadline};
+pub use error::Nwd1QuicError;
+pub use fec::{FEC_MAGIC, FEC_OVERHEAD, FecConfig, FecDecoder, FecEncoder, FecStats};
+pub use fragment::{DatagramReceiver, DatagramSender, FRAG_MAGIC, FragmentConfig};
+pub use headers::{HEADERS_FLAG, HEADERS_VERSION, Headers, set_headers, take_headers};
+pub use interceptor::{Intercept, Interceptor, InterceptorStack};
+pub use limits::FrameLimits;
+pub use pool::{BufferPool, DEFAULT_POOL_BYTES, PoolStats};
+pub use reader::FrameReader;
+pub use router::{Router, UnknownKindPolicy};
+pub use rpc::RpcClient;
+pub use server::{
+    FrameHandler, HANDLER_ERROR_CODE, Nwd1Server, PROTOCOL_ERROR_CODE, ReplySender, ServerLimits,
+};
+pub use service::{ServiceHandler, serve_stream};
+pub use stream::{FrameSink, FrameStream};
+pub use trace::{TRACEPARENT, TraceContext, current_trace_context, with_trace_context};
+pub use uni::{UniAcceptor, send_frame_uni};
 
 const HEADER_LEN: usize = 8;
+const MIN_BODY_LEN: usize = 8 + 1 + 8; // ID + KIND + VER
 const MAX_FRAME_LEN: usize = 8 * 1024 * 1024; // 8 MiB sanity cap to avoid pathological allocations
+const FIXED_LEN: usize = HEADER_LEN + MIN_BODY_LEN; // everything before PAYLOAD
+const INLINE_PAYLOAD_LEN: usize = 1024; // smaller batched payloads are copied next to their header
+const EXTENSION_FLAGS: u64 = DEADLINE_FLAG | HEADERS_FLAG; // VER bits announcing payload extensions
+
+/// Default upper bound on the bytes [`send_frames`] coalesces into a single write.
+pub const DEFAULT_MAX_BATCH_BYTES: usize = 256 * 1024;
+
+/// `frame` with the deadline and trace context in scope applied, or `None` if neither applies.
+fn propagate(frame: &Frame) -> Result<Option<Frame>, Nwd1QuicError> {
+    let traced = trace::inject(frame)?;
+    Ok(deadline::forward(traced.as_ref().unwrap_or(frame))?.or(traced))
+}
+
+/// Whether [`propagate`] may change frames sent from the current task.
+fn propagating() -> bool {
+    current_deadline().is_some() || current_trace_context().is_some()
+}
+
+/// Validate the `MAGIC | LEN` prefix of a frame and return the announced body length.
+#[inline]
+fn parse_header(header: &[u8; HEADER_LEN], limits: &FrameLimits) -> Result<usize, Nwd1QuicError> {
+    // Fast-fail on bad magic to avoid large allocations
+    if &header[..4] != MAGIC {
+        return Err(Nwd1QuicError::BadMagic([header[0], header[1], header[2], header[3]]));
+    }
+
+    // Parse LEN (bytes 4..8) as big-endian u32
+    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+
+    limits.check_len(len)?;
+    Ok(len)
+}
+
+/// Parse the fixed `ID | KIND | VER` fields at the start of a body.
+#[inline]
+fn parse_fixed(mut fields: &[u8]) -> (NetId64, u8, u64) {
+    (NetId64::from_raw(fields.get_u64()), fields.get_u8(), fields.get_u64())
+}
+
+/// Validate the fixed prefix buffered at the start of `buf` and return the announced body length.
+///
+/// Returns `None` until all of `MAGIC | LEN | ID | KIND | VER` is buffered, so callers never
+/// size a buffer from a frame that has not passed every limit.
+fn check_prefix(buf: &[u8], limits: &FrameLimits) -> Result<Option<usize>, Nwd1QuicError> {
+    let Some(header) = buf.first_chunk::<HEADER_LEN>() else {
+        return Ok(None);
+    };
+    let len = parse_header(header, limits)?;
+
+    let Some(fixed) = buf.get(HEADER_LEN..FIXED_LEN) else {
+        return Ok(None);
+    };
+    let (_, kind, ver) = parse_fixed(fixed);
+    limits.check_fixed(len, kind, ver)?;
+    Ok(Some(len))
+}
+
+/// Build a [`Frame`] from a body (`ID | KIND | VER | PAYLOAD`) validated by [`check_prefix`].
+///
+/// The payload is a slice of `body`, so no bytes are copied.
+#[inline]
+fn decode_body(body: Bytes) -> Frame {
+    let (id, kind, ver) = parse_fixed(&body);
+    Frame { id, kind, ver, payload: body.slice(MIN_BODY_LEN..) }
+}
+
+/// Encode the fixed `MAGIC | LEN | ID | KIND | VER` prefix of `frame`.
+#[inline]
+fn encode_header(frame: &Frame) -> [u8; FIXED_LEN] {
+    let mut header = [0u8; FIXED_LEN];
+    let mut dst = &mut header[..];
+    dst.put_slice(MAGIC);
+    dst.put_u32((MIN_BODY_LEN + frame.payload.len()) as u32);
+    dst.put_u64(frame.id.raw());
+    dst.put_u8(frame.kind);
+    dst.put_u64(frame.ver);
+    header
+}
 
+/// Append the encoding of `frame` to `dst`, byte-identical to [`nwd1::encode`].
+#[inline]
+fn encode_into(frame: &Frame, dst: &mut BytesMut) {
+    dst.reserve(FIXED_LEN + frame.payload.len());
+    dst.extend_from_slice(&encode_header(frame));
+    dst.extend_from_slice(&frame.payload);
+}
+
+/// Fill `buf` with the bytes at offset `at` of a frame that is `expected` bytes long.
+///
+/// Returns `None` only if the stream finished cleanly at a frame boundary (`at == 0` and nothing
+/// read); finishing anywhere else is reported as [`Nwd1QuicError::Truncated`].
 #[inline]
 async fn read_exact_opt(
     stream: &mut RecvStream,
     buf: &mut [u8],
-) -> Result<Option<()>, std::io::Error> {
+    at: usize,
+    expected: usize,
+) -> Result<Option<()>, Nwd1QuicError> {
     match stream.read_exact(buf).await {
         Ok(()) => Ok(Some(())),
-        Err(quinn::ReadExactError::FinishedEarly(_)) => Ok(None),
-        Err(quinn::ReadExactError::ReadError(e)) => Err(std::io::Error::from(e)),
+        Err(quinn::ReadExactError::FinishedEarly(0)) if at == 0 => Ok(None),
+        Err(quinn::ReadExactError::FinishedEarly(got)) => {
+            Err(Nwd1QuicError::Truncated { expected, got: at + got })
+        }
+        Err(quinn::ReadExactError::ReadError(e)) => Err(Nwd1QuicError::from(e)),
+    }
+}
+
+/// Read the `len` bytes at offset `at` of a frame that is `expected` bytes long, as chunks
+/// handed out by quinn.
+///
+/// When a single chunk covers the whole range it is returned as is; otherwise the chunks are
+/// gathered into one buffer, so the data is copied at most once.
+async fn read_bytes(
+    stream: &mut RecvStream,
+    len: usize,
+    at: usize,
+    expected: usize,
+) -> Result<Bytes, Nwd1QuicError> {
+    if len == 0 {
+        return Ok(Bytes::new());
+    }
+    let first = match stream.read_chunk(len, true).await? {
+        Some(chunk) => chunk.bytes,
+        None => return Err(Nwd1QuicError::Truncated { expected, got: at }),
+    };
+    if first.len() == len {
+        return Ok(first);
     }
+
+    let mut buf = BytesMut::with_capacity(len);
+    buf.extend_from_slice(&first);
+    while buf.len() < len {
+        match stream.read_chunk(len - buf.len(), true).await? {
+            Some(chunk) => buf.extend_from_slice(&chunk.bytes),
+            None => return Err(Nwd1QuicError::Truncated { expected, got: at + buf.len() }),
+        }
+    }
+    Ok(buf.freeze())
 }
 
 /// Send a single frame over a QUIC bidirectional stream.
 ///
 /// This function writes the encoded frame bytes to the stream and returns immediately. The stream remains open for further writes.
-pub async fn send_frame(stream: &mut SendStream, frame: &Frame) -> Result<(), quinn::WriteError> {
-    let data = encode(frame);
-    stream.write_all(&data).await?;
+///
+/// Only the fixed 25-byte header is encoded; the payload `Bytes` is handed to quinn as a
+/// separate chunk, so it is never copied.
+///
+/// The write runs in a trace-level `send_frame` span with the frame's `id`, `kind`, `ver` and
+/// payload `len`.
+pub async fn send_frame(stream: &mut SendStream, frame: &Frame) -> Result<(), Nwd1QuicError> {
+    write_frame(stream, frame, None).await
+}
+
+/// Send a single frame like [`send_frame`], encoding the header into a buffer from `pool`.
+///
+/// The buffer goes back to the pool once quinn no longer holds the header, i.e. after the peer
+/// acknowledged it.
+pub async fn send_frame_with_pool(
+    stream: &mut SendStream,
+    frame: &Frame,
+    pool: &BufferPool,
+) -> Result<(), Nwd1QuicError> {
+    write_frame(stream, frame, Some(pool)).await
+}
+
+async fn write_frame(
+    stream: &mut SendStream,
+    frame: &Frame,
+    pool: Option<&BufferPool>,
+) -> Result<(), Nwd1QuicError> {
+    let span = tracing::trace_span!(
+        "send_frame",
+        id = frame.id.raw(),
+        kind = frame.kind,
+        ver = frame.ver,
+        len = frame.payload.len(),
+    );
+    let mut buf = PooledBuf::new(pool.cloned());
+    buf.reserve_total(FIXED_LEN);
+    buf.extend_from_slice(&encode_header(frame));
+    let mut chunks = [buf.split().freeze(), frame.payload.clone()];
+    stream.write_all_chunks(&mut chunks).instrument(span).await?;
+    Ok(())
+}
+
+/// Send a burst of frames, coalescing them into as few writes as possible.
+///
+/// Equivalent to calling [`send_frame`] for each frame, but headers and small payloads are
+/// packed into one buffer and handed to quinn together with the larger payloads as a single
+/// vectored write of up to [`DEFAULT_MAX_BATCH_BYTES`].
+pub async fn send_frames<'a>(
+    stream: &mut SendStream,
+    frames: impl IntoIterator<Item = &'a Frame>,
+) -> Result<(), Nwd1QuicError> {
+    send_frames_bounded(stream, frames, DEFAULT_MAX_BATCH_BYTES).await
+}
+
+/// Send a burst of frames like [`send_frames`], writing at most `max_batch_bytes` per write.
+///
+/// A single frame larger than `max_batch_bytes` is still written whole, in a batch of its own.
+///
+/// The writes run in a trace-level `send_frames` span, recording the number of `frames` and
+/// their encoded `len` as they are batched.
+pub async fn send_frames_bounded<'a>(
+    stream: &mut SendStream,
+    frames: impl IntoIterator<Item = &'a Frame>,
+    max_batch_bytes: usize,
+) -> Result<(), Nwd1QuicError> {
+    let span = tracing::trace_span!("send_frames", frames = Empty, len = Empty);
+    write_frames(stream, frames, max_batch_bytes, None, &span).instrument(span.clone()).await
+}
+
+/// Send a burst of frames like [`send_frames_bounded`], packing each batch into a buffer from
+/// `pool`.
+///
+/// Buffers go back to the pool once quinn no longer holds the batches written from them.
+pub async fn send_frames_with_pool<'a>(
+    stream: &mut SendStream,
+    frames: impl IntoIterator<Item = &'a Frame>,
+    max_batch_bytes: usize,
+    pool: &BufferPool,
+) -> Result<(), Nwd1QuicError> {
+    let span = tracing::trace_span!("send_frames", frames = Empty, len = Empty);
+    write_frames(stream, frames, max_batch_bytes, Some(pool), &span).instrument(span.clone()).await
+}
+
+async fn write_frames<'a>(
+    stream: &mut SendStream,
+    frames: impl IntoIterator<Item = &'a Frame>,
+    max_batch_bytes: usize,
+    pool: Option<&BufferPool>,
+    span: &Span,
+) -> Result<(), Nwd1QuicError> {
+    let mut frames = frames.into_iter().peekable();
+    let mut batch = Vec::new();
+    let mut chunks = Vec::new();
+    let mut inline = PooledBuf::new(pool.cloned());
+    let (mut count, mut total) = (0usize, 0usize);
+
+    while frames.peek().is_some() {
+        // Gather a batch first, so its headers and small payloads fit one buffer
+        let (mut batched, mut inline_len) = (0, 0);
+        while let Some(frame) = frames.next_if(|frame| {
+            batched == 0 || batched + FIXED_LEN + frame.payload.len() <= max_batch_bytes
+        }) {
+            batched += FIXED_LEN + frame.payload.len();
+            inline_len += FIXED_LEN;
+            if frame.payload.len() <= INLINE_PAYLOAD_LEN {
+                inline_len += frame.payload.len();
+            }
+            batch.push(frame);
+        }
+        count += batch.len();
+        total += batched;
+        span.record("frames", count).record("len", total);
+
+        inline.reserve_total(inline_len);
+        for frame in batch.drain(..) {
+            inline.extend_from_slice(&encode_header(frame));
+            if frame.payload.len() <= INLINE_PAYLOAD_LEN {
+                inline.extend_from_slice(&frame.payload);
+            } else {
+                chunks.push(inline.split().freeze());
+                chunks.push(frame.payload.clone());
+            }
+        }
+        if !inline.is_empty() {
+            chunks.push(inline.split().freeze());
+        }
+        stream.write_all_chunks(&mut chunks).await?;
+        chunks.clear();
+    }
     Ok(())
 }
 
 /// Receive a single frame from a QUIC bidirectional stream.
 ///
 /// This function reads until a complete frame is received and decodes it.
-/// It returns `None` if the stream ends gracefully.
-pub async fn recv_frame(stream: &mut RecvStream) -> Result<Option<Frame>, std::io::Error> {
+/// It returns `None` if the stream ends gracefully between frames; a stream that finishes
+/// mid-frame is reported as [`Nwd1QuicError::Truncated`].
+///
+/// The returned payload shares the buffer quinn received it in whenever the body arrives as a
+/// single chunk.
+///
+/// Not cancel safe: use [`FrameReader`] when receiving inside `tokio::select!`.
+pub async fn recv_frame(stream: &mut RecvStream) -> Result<Option<Frame>, Nwd1QuicError> {
+    recv_frame_with_limits(stream, &FrameLimits::default()).await
+}
+
+/// Receive a single frame like [`recv_frame`], enforcing `limits`.
+///
+/// `LEN` is checked right after the header and the `KIND` / `VER` rules right after the fixed
+/// body fields, so a rejected frame never causes its payload to be read or allocated.
+///
+/// The read runs in a trace-level `recv_frame` span, recording `id`, `kind`, `ver` and payload
+/// `len` once the fixed fields arrive.
+pub async fn recv_frame_with_limits(
+    stream: &mut RecvStream,
+    limits: &FrameLimits,
+) -> Result<Option<Frame>, Nwd1QuicError> {
+    let span =
+        tracing::trace_span!("recv_frame", id = Empty, kind = Empty, ver = Empty, len = Empty);
+    read_frame(stream, limits, &span).instrument(span.clone()).await
+}
+
+async fn read_frame(
+    stream: &mut RecvStream,
+    limits: &FrameLimits,
+    span: &Span,
+) -> Result<Option<Frame>, Nwd1QuicError> {
     let mut header = [0u8; HEADER_LEN];
-    if read_exact_opt(stream, &mut header).await?.is_none() {
+    if read_exact_opt(stream, &mut header, 0, HEADER_LEN).await?.is_none() {
         return Ok(None);
     }
 
-    // Fast-fail on bad magic to avoid large allocations
-    if &header[..4] != MAGIC {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "nwd1 bad magic"));
-    }
+    let len = parse_header(&header, limits)?;
+    let expected = HEADER_LEN + len;
 
-    // Parse LEN (bytes 4..8) as big-endian u32
-    let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let mut fixed = [0u8; MIN_BODY_LEN];
+    read_exact_opt(stream, &mut fixed, HEADER_LEN, expected).await?;
 
-    if len > MAX_FRAME_LEN {
-        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "nwd1 frame too large"));
-    }
+    let (id, kind, ver) = parse_fixed(&fixed);
+    span.record("id", id.raw()).record("kind", kind).record("ver", ver);
+    span.record("len", len - MIN_BODY_LEN);
+    limits.check_fixed(len, kind, ver)?;
 
-    let mut body = vec![0u8; len];
-    if read_exact_opt(stream, &mut body).await?.is_none() {
-        return Ok(None);
-    }
+    let payload = read_bytes(stream, len - MIN_BODY_LEN, FIXED_LEN, expected).await?;
 
-    let mut buf = BytesMut::with_capacity(8 + len);
-    buf.extend_from_slice(&header);
-    buf.extend_from_slice(&body);
-
-    let frame = match decode(&buf.freeze()) {
-        Ok(f) => f,
-        Err(_) => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "nwd1 decode error",
-            ));
-        }
-    };
-    Ok(Some(frame))
+    Ok(Some(Frame { id, kind, ver, payload }))
 }
 
 /// Minimal self-test to ensure the functions compile and link.
 #[cfg(test)]
 mod tests {
-	use netid64::NetId64;
     use super::*;
     use bytes::Bytes;
+    use nwd1::{decode, encode};
 
     #[tokio::test]
     async fn encode_decode_roundtrip() {
-        let frame = Frame {
-            id: NetId64::make(1, 7, 42),
-            kind: 1,
-            ver: 1,
-            payload: Bytes::from_static(b"ping"),
-        };
+        let frame = test_util::frame(42, 1, "ping");
 
         // Encoding must produce non-empty bytes
         let data = encode(&frame);
@@ -100,4 +420,167 @@ mod tests {
         assert_eq!(decoded.id.raw(), frame.id.raw());
         assert_eq!(decoded.payload, frame.payload);
     }
+
+    #[tokio::test]
+    async fn recv_frame_small_and_multi_chunk() {
+        let lb = test_util::loopback().await;
+        let small = test_util::frame(1, 1, "ping");
+        let large = test_util::frame(2, 2, vec![0xAB; 1 << 20]);
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        let writer = tokio::spawn(async move {
+            send_frame(&mut send, &small).await.unwrap();
+            send_frame(&mut send, &large).await.unwrap();
+            send.finish().unwrap();
+        });
+
+        let mut recv = lb.server.accept_uni().await.unwrap();
+        let first = recv_frame(&mut recv).await.unwrap().unwrap();
+        assert_eq!((first.id.counter(), first.kind, &first.payload[..]), (1, 1, &b"ping"[..]));
+        let second = recv_frame(&mut recv).await.unwrap().unwrap();
+        assert_eq!((second.id.counter(), second.kind, second.ver), (2, 2, 1));
+        assert_eq!(second.payload.len(), 1 << 20);
+        assert!(second.payload.iter().all(|&b| b == 0xAB));
+        assert!(recv_frame(&mut recv).await.unwrap().is_none());
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_frame_matches_encode() {
+        let lb = test_util::loopback().await;
+        let frames = [
+            Frame { ver: 9, ..test_util::frame(1, 3, Bytes::new()) },
+            test_util::frame(2, 4, vec![1; 300_000]),
+        ];
+
+        let mut expected = BytesMut::new();
+        for frame in &frames {
+            expected.extend_from_slice(&encode(frame));
+        }
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        for frame in &frames {
+            send_frame(&mut send, frame).await.unwrap();
+        }
+        send.finish().unwrap();
+
+        let mut recv = lb.server.accept_uni().await.unwrap();
+        let received = recv.read_to_end(1 << 20).await.unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn send_frames_coalesces_bursts() {
+        let lb = test_util::loopback().await;
+        let mut frames: Vec<Frame> = (0..300)
+            .map(|counter| test_util::frame(counter, 5, format!("status {counter}")))
+            .collect();
+        frames.insert(150, test_util::frame(999, 6, vec![2; 64 * 1024]));
+
+        let mut expected = BytesMut::new();
+        for frame in &frames {
+            expected.extend_from_slice(&encode(frame));
+        }
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        send_frames_bounded(&mut send, &frames, 4096).await.unwrap();
+        send_frames(&mut send, &frames[..10]).await.unwrap();
+        send_frames(&mut send, []).await.unwrap();
+        send.finish().unwrap();
+        for frame in &frames[..10] {
+            expected.extend_from_slice(&encode(frame));
+        }
+
+        let mut recv = lb.server.accept_uni().await.unwrap();
+        let received = recv.read_to_end(1 << 20).await.unwrap();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn send_path_draws_from_pool() {
+        let lb = test_util::loopback().await;
+        let pool = BufferPool::default();
+        let frames: Vec<Frame> = [16, 4096, 16]
+            .into_iter()
+            .enumerate()
+            .map(|(counter, len)| test_util::frame(counter as u64, 5, vec![3; len]))
+            .collect();
+
+        let mut expected = BytesMut::new();
+        let mut send = lb.client.open_uni().await.unwrap();
+        for _ in 0..2 {
+            send_frame_with_pool(&mut send, &frames[0], &pool).await.unwrap();
+            // Each batch fits in what the first one left of its buffer
+            send_frames_with_pool(&mut send, &frames, 4096, &pool).await.unwrap();
+            for frame in [&frames[0]].into_iter().chain(&frames) {
+                expected.extend_from_slice(&encode(frame));
+            }
+        }
+        send.finish().unwrap();
+        let mut recv = lb.server.accept_uni().await.unwrap();
+        assert_eq!(recv.read_to_end(1 << 20).await.unwrap(), expected);
+
+        // Buffers come back once the peer acknowledged what was written from them
+        send.stopped().await.unwrap();
+        let stats = pool.stats();
+        assert_eq!(stats.hits + stats.misses, 4);
+        assert_eq!(
+            (stats.outstanding_bytes, stats.pooled_bytes),
+            (0, stats.misses as usize * 4096)
+        );
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        send_frame_with_pool(&mut send, &frames[0], &pool).await.unwrap();
+        assert_eq!(pool.stats().hits, stats.hits + 1);
+    }
+
+    #[tokio::test]
+    async fn recv_frame_enforces_limits() {
+        let lb = test_util::loopback().await;
+        let frame = |kind, ver, len| Frame { ver, ..test_util::frame(3, kind, vec![0; len]) };
+
+        let mut limits = FrameLimits::with_max_len(1024);
+        limits.kind_max_len.insert(9, 64 * 1024);
+        limits.ver = Some(1..=1);
+
+        let cases =
+            [(frame(9, 1, 32 * 1024), true), (frame(1, 1, 2048), false), (frame(9, 2, 16), false)];
+        for (frame, accepted) in cases {
+            let mut send = lb.client.open_uni().await.unwrap();
+            send_frame(&mut send, &frame).await.unwrap();
+            send.finish().unwrap();
+
+            let mut recv = lb.server.accept_uni().await.unwrap();
+            let result = recv_frame_with_limits(&mut recv, &limits).await;
+            assert_eq!(result.is_ok(), accepted);
+            if let Ok(received) = result {
+                assert_eq!(received.unwrap().payload.len(), 32 * 1024);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn recv_frame_reports_truncation() {
+        let lb = test_util::loopback().await;
+        let data = encode(&test_util::frame(4, 1, "cut short"));
+
+        // Clean end, then EOF inside the header, the fixed fields and the payload
+        for cut in [0, 5, 12, data.len() - 3] {
+            let mut send = lb.client.open_uni().await.unwrap();
+            send.write_all(&data).await.unwrap();
+            send.write_all(&data[..cut]).await.unwrap();
+            send.finish().unwrap();
+
+            let mut recv = lb.server.accept_uni().await.unwrap();
+            assert!(recv_frame(&mut recv).await.unwrap().is_some());
+            match recv_frame(&mut recv).await {
+                Ok(None) => assert_eq!(cut, 0),
+                Err(Nwd1QuicError::Truncated { expected, got }) => {
+                    assert_eq!(expected, if cut < HEADER_LEN { HEADER_LEN } else { data.len() });
+                    assert_eq!(got, cut);
+                }
+                _ => panic!("unexpected result for cut at {cut}"),
+            }
+        }
+    }
 }
@@ -0,0 +1,117 @@
+//! Receive-side limits on frame size, `KIND` and `VER`.
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::{EXTENSION_FLAGS, MAX_FRAME_LEN, MIN_BODY_LEN, Nwd1QuicError};
+
+/// Limits enforced on received frames before their payload is allocated.
+///
+/// `LEN` is checked as soon as the 8-byte header is read; the `KIND` and `VER` rules once the
+/// 17 fixed body bytes follow. Lengths are body lengths, i.e. the `LEN` field of the header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameLimits {
+    /// Largest accepted `LEN`. Defaults to 8 MiB.
+    pub max_len: usize,
+    /// Smallest accepted `LEN`. Values below the 17 bytes of `ID | KIND | VER` are raised to it.
+    pub min_len: usize,
+    /// Per-`KIND` replacement for `max_len`, which may be larger or smaller than it.
+    pub kind_max_len: HashMap<u8, usize>,
+    /// Accepted `VER` values; any version is accepted when `None`. Extension flag bits such as
+    /// [`DEADLINE_FLAG`](crate::DEADLINE_FLAG) are masked off before the check.
+    pub ver: Option<RangeInclusive<u64>>,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        Self {
+            max_len: MAX_FRAME_LEN,
+            min_len: MIN_BODY_LEN,
+            kind_max_len: HashMap::new(),
+            ver: None,
+        }
+    }
+}
+
+impl FrameLimits {
+    /// Default limits with `max_len` replaced.
+    pub fn with_max_len(max_len: usize) -> Self {
+        Self { max_len, ..Self::default() }
+    }
+
+    /// Check `LEN` against the largest limit any `KIND` may use.
+    pub(crate) fn check_len(&self, len: usize) -> Result<(), Nwd1QuicError> {
+        let limit = self.largest_len();
+        if len > limit {
+            return Err(Nwd1QuicError::FrameTooLarge { len, limit });
+        }
+        let min = self.min_len.max(MIN_BODY_LEN);
+        if len < min {
+            return Err(Nwd1QuicError::FrameTooSmall { len, min });
+        }
+        Ok(())
+    }
+
+    /// The largest `LEN` any `KIND` may use.
+    pub(crate) fn largest_len(&self) -> usize {
+        self.kind_max_len.values().copied().fold(self.max_len, usize::max)
+    }
+
+    /// Check the `KIND`-specific length limit and the `VER` range.
+    pub(crate) fn check_fixed(&self, len: usize, kind: u8, ver: u64) -> Result<(), Nwd1QuicError> {
+        let limit = self.kind_max_len.get(&kind).copied().unwrap_or(self.max_len);
+        if len > limit {
+            return Err(Nwd1QuicError::FrameTooLarge { len, limit });
+        }
+        let ver = ver & !EXTENSION_FLAGS;
+        if let Some(range) = &self.ver
+            && !range.contains(&ver)
+        {
+            return Err(Nwd1QuicError::VersionNotAllowed { ver });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_matches_previous_cap() {
+        let limits = FrameLimits::default();
+        assert!(limits.check_len(MAX_FRAME_LEN).is_ok());
+        assert!(limits.check_len(MAX_FRAME_LEN + 1).is_err());
+        assert!(limits.check_len(MIN_BODY_LEN - 1).is_err());
+        assert!(limits.check_fixed(MAX_FRAME_LEN, 0xFF, u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn kind_and_ver_rules() {
+        let mut limits = FrameLimits::with_max_len(64 * 1024);
+        limits.kind_max_len.insert(9, 64 * 1024 * 1024);
+        limits.kind_max_len.insert(2, 1024);
+        limits.ver = Some(1..=2);
+
+        // LEN alone may only be rejected against the largest per-kind limit
+        assert!(limits.check_len(32 * 1024 * 1024).is_ok());
+        assert!(limits.check_len(65 * 1024 * 1024).is_err());
+
+        assert!(limits.check_fixed(32 * 1024 * 1024, 9, 1).is_ok());
+        assert!(matches!(
+            limits.check_fixed(32 * 1024 * 1024, 1, 1),
+            Err(Nwd1QuicError::FrameTooLarge { limit: 65536, .. })
+        ));
+        assert!(matches!(
+            limits.check_fixed(2048, 2, 1),
+            Err(Nwd1QuicError::FrameTooLarge { limit: 1024, .. })
+        ));
+        assert!(matches!(
+            limits.check_fixed(2048, 1, 3),
+            Err(Nwd1QuicError::VersionNotAllowed { ver: 3 })
+        ));
+        assert!(
+            limits.check_fixed(2048, 1, 2 | crate::DEADLINE_FLAG | crate::HEADERS_FLAG).is_ok()
+        );
+    }
+}
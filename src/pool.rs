@@ -0,0 +1,354 @@
+//! Size-classed `BytesMut` pool shared by frame readers and the send path.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::{Bytes, BytesMut};
+
+/// Buffer capacities handed out by the pool, from 4 KiB to 16 MiB.
+const SIZE_CLASSES: [usize; 7] =
+    [4 * 1024, 16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024, 4 * 1024 * 1024, 16 * 1024 * 1024];
+
+/// Default bound on idle bytes kept by a [`BufferPool`].
+pub const DEFAULT_POOL_BYTES: usize = 64 * 1024 * 1024;
+
+/// A pool of reusable [`BytesMut`] buffers.
+///
+/// Requests are rounded up to a fixed size class so returned buffers can serve later requests
+/// of similar size. Idle buffers are kept until their combined capacity reaches the configured
+/// bound; anything beyond that is simply freed. Cloning is cheap and clones share the same
+/// buffers, so one pool can back every connection of an endpoint.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    max_pooled_bytes: usize,
+    idle: Mutex<Idle>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    outstanding_bytes: AtomicUsize,
+}
+
+#[derive(Debug, Default)]
+struct Idle {
+    classes: [Vec<BytesMut>; SIZE_CLASSES.len()],
+    bytes: usize,
+    /// Released buffers whose storage is still shared with split-off `Bytes`, with the capacity
+    /// they were acquired with.
+    draining: Vec<(BytesMut, usize)>,
+    /// Address and capacity of the buffers handed out by [`BufferPool::acquire`], so that
+    /// [`BufferPool::release`] can tell them from buffers allocated elsewhere.
+    lent: HashMap<usize, usize>,
+}
+
+impl Idle {
+    /// File a cleared buffer under the largest size class its capacity covers, unless it is
+    /// smaller than every class or the pool is full.
+    fn file(&mut self, buf: BytesMut, max_pooled_bytes: usize) {
+        let capacity = buf.capacity();
+        let Some(class) = SIZE_CLASSES.iter().rposition(|&size| size <= capacity) else {
+            return;
+        };
+        if self.bytes + capacity > max_pooled_bytes {
+            return;
+        }
+        self.bytes += capacity;
+        self.classes[class].push(buf);
+    }
+}
+
+/// Snapshot of [`BufferPool`] counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Acquisitions served by an idle buffer.
+    pub hits: u64,
+    /// Acquisitions that had to allocate.
+    pub misses: u64,
+    /// Capacity of buffers acquired and not yet released, or released while still shared with
+    /// frames split off them.
+    pub outstanding_bytes: usize,
+    /// Capacity of idle buffers held by the pool.
+    pub pooled_bytes: usize,
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_POOL_BYTES)
+    }
+}
+
+impl BufferPool {
+    /// Create a pool keeping at most `max_pooled_bytes` of idle buffer capacity.
+    pub fn new(max_pooled_bytes: usize) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                max_pooled_bytes,
+                idle: Mutex::new(Idle::default()),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+                outstanding_bytes: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    /// Take an empty buffer with at least `min_capacity` bytes of capacity.
+    ///
+    /// Requests larger than the biggest size class are allocated exactly and not counted as
+    /// pool hits. The buffer counts as outstanding until it is [released](Self::release).
+    pub fn acquire(&self, min_capacity: usize) -> BytesMut {
+        let buf = self.acquire_untracked(min_capacity);
+        self.inner.idle.lock().unwrap().lent.insert(buf.as_ptr() as usize, buf.capacity());
+        buf
+    }
+
+    /// Take a buffer like [`acquire`](Self::acquire), for a caller that hands its capacity back
+    /// to [`release_acquired`](Self::release_acquired) itself.
+    pub(crate) fn acquire_untracked(&self, min_capacity: usize) -> BytesMut {
+        let buf = match SIZE_CLASSES.iter().position(|&size| size >= min_capacity) {
+            Some(class) => {
+                let mut idle = self.inner.idle.lock().unwrap();
+                self.reclaim(&mut idle);
+                match idle.classes[class].pop() {
+                    Some(buf) => {
+                        idle.bytes -= buf.capacity();
+                        drop(idle);
+                        self.inner.hits.fetch_add(1, Ordering::Relaxed);
+                        buf
+                    }
+                    None => {
+                        drop(idle);
+                        self.inner.misses.fetch_add(1, Ordering::Relaxed);
+                        BytesMut::with_capacity(SIZE_CLASSES[class])
+                    }
+                }
+            }
+            None => {
+                self.inner.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(min_capacity)
+            }
+        };
+        self.inner.outstanding_bytes.fetch_add(buf.capacity(), Ordering::Relaxed);
+        buf
+    }
+
+    /// Return a buffer to the pool.
+    ///
+    /// The buffer is cleared and filed under the largest size class its capacity covers. It is
+    /// dropped instead if it is smaller than every class or the pool is full. Buffers that did
+    /// not come from [`acquire`](Self::acquire) are welcome too, and leave the outstanding
+    /// bytes unchanged.
+    pub fn release(&self, mut buf: BytesMut) {
+        let mut idle = self.inner.idle.lock().unwrap();
+        match idle.lent.remove(&(buf.as_ptr() as usize)) {
+            Some(acquired) => {
+                drop(idle);
+                self.release_acquired(buf, acquired);
+            }
+            None => {
+                buf.clear();
+                idle.file(buf, self.inner.max_pooled_bytes);
+            }
+        }
+    }
+
+    /// Return a buffer that was handed out with `acquired` bytes of capacity.
+    ///
+    /// Its capacity may have shrunk since, when frames were split off the front. While those
+    /// frames are alive the buffer stays outstanding; it is filed once its storage is unshared.
+    pub(crate) fn release_acquired(&self, mut buf: BytesMut, acquired: usize) {
+        buf.clear();
+        let mut idle = self.inner.idle.lock().unwrap();
+        if buf.try_reclaim(acquired) {
+            self.forget(acquired);
+            idle.file(buf, self.inner.max_pooled_bytes);
+        } else {
+            idle.draining.push((buf, acquired));
+        }
+    }
+
+    /// Stop counting `acquired` bytes as outstanding, without taking a buffer back.
+    fn forget(&self, acquired: usize) {
+        let _ =
+            self.inner.outstanding_bytes.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(acquired))
+            });
+    }
+
+    /// File the draining buffers no longer shared with anything.
+    fn reclaim(&self, idle: &mut Idle) {
+        let mut draining = std::mem::take(&mut idle.draining);
+        draining.retain_mut(|(buf, acquired)| {
+            if !buf.try_reclaim(*acquired) {
+                return true;
+            }
+            self.forget(*acquired);
+            idle.file(std::mem::take(buf), self.inner.max_pooled_bytes);
+            false
+        });
+        idle.draining = draining;
+    }
+
+    /// Return the storage behind `bytes` to the pool if this is its last reference.
+    ///
+    /// Useful for handing a received payload back once the application is done with it; see
+    /// [`release`](Self::release).
+    pub fn recycle(&self, bytes: Bytes) {
+        if let Ok(buf) = bytes.try_into_mut() {
+            self.release(buf);
+        }
+    }
+
+    /// Current counters.
+    pub fn stats(&self) -> PoolStats {
+        let mut idle = self.inner.idle.lock().unwrap();
+        self.reclaim(&mut idle);
+        PoolStats {
+            hits: self.inner.hits.load(Ordering::Relaxed),
+            misses: self.inner.misses.load(Ordering::Relaxed),
+            outstanding_bytes: self.inner.outstanding_bytes.load(Ordering::Relaxed),
+            pooled_bytes: idle.bytes,
+        }
+    }
+}
+
+/// A `BytesMut` that grows into pooled buffers and goes back to its pool on drop.
+///
+/// Without a pool it is a plain `BytesMut`.
+#[derive(Debug, Default)]
+pub(crate) struct PooledBuf {
+    buf: BytesMut,
+    pool: Option<BufferPool>,
+    acquired: usize,
+}
+
+impl PooledBuf {
+    pub(crate) fn new(pool: Option<BufferPool>) -> Self {
+        Self { buf: BytesMut::new(), pool, acquired: 0 }
+    }
+
+    pub(crate) fn is_pooled(&self) -> bool {
+        self.pool.is_some()
+    }
+
+    /// Make room for `total` bytes, moving the contents into a pooled buffer if needed.
+    pub(crate) fn reserve_total(&mut self, total: usize) {
+        let Some(pool) = &self.pool else { return };
+        if self.buf.capacity() >= total {
+            return;
+        }
+        let mut fresh = pool.acquire_untracked(total);
+        fresh.extend_from_slice(&self.buf);
+        let acquired = std::mem::replace(&mut self.acquired, fresh.capacity());
+        pool.release_acquired(std::mem::replace(&mut self.buf, fresh), acquired);
+    }
+
+    /// Take the buffered bytes, leaving an empty buffer behind.
+    ///
+    /// The bytes are no longer tracked by the pool.
+    pub(crate) fn take(&mut self) -> BytesMut {
+        if let Some(pool) = &self.pool {
+            pool.forget(std::mem::take(&mut self.acquired));
+        }
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            pool.release_acquired(std::mem::take(&mut self.buf), self.acquired);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuses_released_buffers() {
+        let pool = BufferPool::default();
+
+        let buf = pool.acquire(100);
+        assert_eq!(buf.capacity(), 4 * 1024);
+        assert_eq!(pool.stats().outstanding_bytes, 4 * 1024);
+        pool.release(buf);
+
+        let mut buf = pool.acquire(4 * 1024);
+        assert!(buf.is_empty());
+        buf.extend_from_slice(b"reused");
+        pool.release(buf);
+        assert!(pool.acquire(1).is_empty());
+
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses), (2, 1));
+        assert_eq!(stats.outstanding_bytes, 4 * 1024);
+        assert_eq!(stats.pooled_bytes, 0);
+    }
+
+    #[test]
+    fn bounds_pooled_bytes() {
+        let pool = BufferPool::new(32 * 1024);
+        let bufs: Vec<_> = (0..3).map(|_| pool.acquire(16 * 1024)).collect();
+        bufs.into_iter().for_each(|buf| pool.release(buf));
+
+        let stats = pool.stats();
+        assert_eq!(stats.pooled_bytes, 32 * 1024);
+        assert_eq!(stats.outstanding_bytes, 0);
+
+        // Oversized requests bypass the classes entirely
+        let huge = pool.acquire(32 * 1024 * 1024);
+        assert_eq!(huge.capacity(), 32 * 1024 * 1024);
+        pool.release(huge);
+        assert_eq!(pool.stats().pooled_bytes, 32 * 1024);
+    }
+
+    #[test]
+    fn recycles_unique_bytes() {
+        let pool = BufferPool::default();
+        let mut buf = pool.acquire(8 * 1024);
+        buf.extend_from_slice(&[1; 128]);
+        let frozen = buf.freeze();
+        let shared = frozen.clone();
+
+        pool.recycle(frozen);
+        assert_eq!(pool.stats().pooled_bytes, 0);
+        pool.recycle(shared);
+        assert_eq!(pool.stats().pooled_bytes, 16 * 1024);
+        assert_eq!(pool.stats().outstanding_bytes, 0);
+    }
+
+    #[test]
+    fn files_foreign_buffers_without_counting_them() {
+        let pool = BufferPool::default();
+        let held = pool.acquire(100);
+
+        pool.recycle(Bytes::from(vec![0; 20_000]));
+        pool.release(BytesMut::with_capacity(64 * 1024));
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding_bytes, stats.pooled_bytes), (4 * 1024, 20_000 + 64 * 1024));
+
+        pool.release(held);
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding_bytes, stats.pooled_bytes), (0, 20_000 + 68 * 1024));
+    }
+}
@@ -0,0 +1,242 @@
+//! Buffered, cancel-safe frame reading.
+
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+
+use bytes::BytesMut;
+use nwd1::Frame;
+use quinn::RecvStream;
+use tokio_util::codec::Decoder;
+use tokio_util::io::poll_read_buf;
+use tracing::Instrument;
+use tracing::field::Empty;
+
+use crate::pool::PooledBuf;
+use crate::{
+    BufferPool, FIXED_LEN, FrameLimits, HEADER_LEN, Nwd1Codec, Nwd1QuicError, check_prefix,
+};
+
+/// Reads frames from a [`RecvStream`] through a persistent buffer.
+///
+/// Unlike [`recv_frame`](crate::recv_frame), bytes taken off the stream are kept in the reader
+/// between calls, so an interrupted [`next_frame`](FrameReader::next_frame) never loses data.
+#[derive(Debug)]
+pub struct FrameReader {
+    stream: RecvStream,
+    buf: PooledBuf,
+    codec: Nwd1Codec,
+}
+
+impl FrameReader {
+    /// Wrap a receive stream.
+    pub fn new(stream: RecvStream) -> Self {
+        Self { stream, buf: PooledBuf::new(None), codec: Nwd1Codec::new() }
+    }
+
+    /// Wrap a receive stream, taking read buffers from `pool`.
+    ///
+    /// Buffers are sized for the frame being received once its header is known, and the
+    /// current buffer goes back to the pool when the reader is dropped.
+    pub fn with_pool(stream: RecvStream, pool: BufferPool) -> Self {
+        Self { stream, buf: PooledBuf::new(Some(pool)), codec: Nwd1Codec::new() }
+    }
+
+    /// Receive the next frame.
+    ///
+    /// Returns `None` once the stream ends at a frame boundary, and
+    /// [`Nwd1QuicError::Truncated`] if it ends inside a frame.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel safe. If it is used as the event in a `tokio::select!` statement
+    /// and some other branch completes first, any partially received header or body stays
+    /// buffered and the next call resumes the same frame.
+    ///
+    /// Each call runs in a trace-level `next_frame` span, recording the frame's `id`, `kind`,
+    /// `ver` and payload `len` once it is decoded.
+    pub async fn next_frame(&mut self) -> Result<Option<Frame>, Nwd1QuicError> {
+        let span =
+            tracing::trace_span!("next_frame", id = Empty, kind = Empty, ver = Empty, len = Empty);
+        let frame =
+            std::future::poll_fn(|cx| self.poll_next_frame(cx)).instrument(span.clone()).await?;
+        if let Some(frame) = &frame {
+            span.record("id", frame.id.raw()).record("kind", frame.kind).record("ver", frame.ver);
+            span.record("len", frame.payload.len());
+        }
+        Ok(frame)
+    }
+
+    /// Poll for the next frame.
+    ///
+    /// Bytes are only ever moved from the stream into the internal buffer, so returning
+    /// `Poll::Pending` keeps any partial frame for the next poll.
+    pub fn poll_next_frame(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<Frame>, Nwd1QuicError>> {
+        loop {
+            if self.buf.is_pooled() {
+                self.reserve_frame()?;
+            }
+            if let Some(frame) = self.codec.decode(&mut self.buf)? {
+                return Poll::Ready(Ok(Some(frame)));
+            }
+
+            if ready!(poll_read_buf(Pin::new(&mut self.stream), cx, &mut *self.buf))? == 0 {
+                // Reports a frame cut short by the end of the stream as truncated
+                return Poll::Ready(self.codec.decode_eof(&mut self.buf));
+            }
+        }
+    }
+
+    /// Size the pooled buffer for the frame in progress before the codec reserves on its own.
+    fn reserve_frame(&mut self) -> Result<(), Nwd1QuicError> {
+        let total = match check_prefix(&self.buf, self.codec.limits())? {
+            Some(len) => HEADER_LEN + len,
+            None => FIXED_LEN,
+        };
+        self.buf.reserve_total(total);
+        Ok(())
+    }
+
+    /// Enforce `limits` on the frames read from now on.
+    pub fn set_limits(&mut self, limits: FrameLimits) {
+        self.codec = Nwd1Codec::with_limits(limits);
+    }
+
+    /// The limits enforced on received frames.
+    pub fn limits(&self) -> &FrameLimits {
+        self.codec.limits()
+    }
+
+    /// Get a reference to the underlying stream.
+    pub fn get_ref(&self) -> &RecvStream {
+        &self.stream
+    }
+
+    /// Get a mutable reference to the underlying stream.
+    ///
+    /// Reading from the stream directly desynchronises the reader.
+    pub fn get_mut(&mut self) -> &mut RecvStream {
+        &mut self.stream
+    }
+
+    /// Consume the reader, returning the stream and any buffered bytes not yet decoded.
+    pub fn into_parts(mut self) -> (RecvStream, BytesMut) {
+        (self.stream, self.buf.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, loopback};
+    use bytes::Bytes;
+    use netid64::NetId64;
+    use nwd1::encode;
+
+    #[tokio::test]
+    async fn resumes_frame_after_cancellation() {
+        let lb = loopback().await;
+        let data = encode(&frame(42, 1, "resumed after select"));
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        send.write_all(&data[..12]).await.unwrap();
+        let mut reader = FrameReader::new(lb.server.accept_uni().await.unwrap());
+
+        // Read until the header and part of the body are buffered, then give up on the frame
+        std::future::poll_fn(|cx| {
+            assert!(reader.poll_next_frame(cx).is_pending(), "frame completed early");
+            if reader.buf.len() < 12 { Poll::Pending } else { Poll::Ready(()) }
+        })
+        .await;
+        assert_eq!(&reader.buf[..], &data[..12]);
+
+        send.write_all(&data[12..]).await.unwrap();
+        send.finish().unwrap();
+
+        let received = reader.next_frame().await.unwrap().unwrap();
+        assert_eq!(received.id.raw(), NetId64::make(1, 7, 42).raw());
+        assert_eq!(received.payload, Bytes::from_static(b"resumed after select"));
+        assert!(reader.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn splits_coalesced_frames() {
+        let lb = loopback().await;
+        let mut data = BytesMut::new();
+        for counter in 0..3 {
+            data.extend_from_slice(&encode(&frame(counter, 1, "burst")));
+        }
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        send.write_all(&data).await.unwrap();
+        send.finish().unwrap();
+
+        let mut reader = FrameReader::new(lb.server.accept_uni().await.unwrap());
+        for counter in 0..3 {
+            assert_eq!(reader.next_frame().await.unwrap().unwrap().id.counter(), counter);
+        }
+        assert!(reader.next_frame().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_truncated_frames() {
+        let lb = loopback().await;
+        let data = encode(&frame(42, 1, "cut short"));
+
+        for (cut, expected) in [(5, HEADER_LEN), (data.len() - 1, data.len())] {
+            let mut send = lb.client.open_uni().await.unwrap();
+            send.write_all(&data[..cut]).await.unwrap();
+            send.finish().unwrap();
+
+            let mut reader = FrameReader::new(lb.server.accept_uni().await.unwrap());
+            let Err(err) = reader.next_frame().await else { panic!("truncated frame accepted") };
+            assert!(
+                matches!(err, Nwd1QuicError::Truncated { expected: e, got } if e == expected && got == cut)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn draws_buffers_from_pool() {
+        let lb = loopback().await;
+        let pool = BufferPool::default();
+        let payload = Bytes::from(vec![7; 100 * 1024]);
+
+        let mut send = lb.client.open_uni().await.unwrap();
+        let writer = tokio::spawn(async move {
+            for counter in 0..4 {
+                let frame = frame(counter, 1, payload.clone());
+                crate::send_frame(&mut send, &frame).await.unwrap();
+            }
+            send.finish().unwrap();
+        });
+
+        let mut reader =
+            FrameReader::with_pool(lb.server.accept_uni().await.unwrap(), pool.clone());
+        let mut frames = Vec::new();
+        while let Some(frame) = reader.next_frame().await.unwrap() {
+            assert_eq!(frame.id.counter(), frames.len() as u64);
+            assert_eq!(frame.payload.len(), 100 * 1024);
+            frames.push(frame);
+        }
+        assert_eq!(frames.len(), 4);
+        writer.await.unwrap();
+
+        // A 4 KiB buffer for the first header, then a 256 KiB one per two frames. The first of
+        // those still backs the frames held here, so it is not pooled yet.
+        let stats = pool.stats();
+        assert_eq!((stats.hits, stats.misses), (0, 3));
+        assert_eq!((stats.outstanding_bytes, stats.pooled_bytes), (512 * 1024, 4 * 1024));
+
+        drop(frames);
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding_bytes, stats.pooled_bytes), (256 * 1024, 260 * 1024));
+        drop(reader);
+        let stats = pool.stats();
+        assert_eq!((stats.outstanding_bytes, stats.pooled_bytes), (0, 516 * 1024));
+        assert_eq!(pool.acquire(200 * 1024).capacity(), 256 * 1024);
+        assert_eq!(pool.stats().hits, 1);
+    }
+}
@@ -0,0 +1,206 @@
+//! Dispatch of frames to handlers by `KIND`.
+
+use std::fmt;
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use nwd1::Frame;
+
+use crate::{FrameHandler, Nwd1QuicError, ReplySender};
+
+type BoxFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Nwd1QuicError>> + Send + 'a>>;
+
+/// A [`FrameHandler`] with its future type erased, so handlers of different types can share a
+/// table.
+trait ErasedHandler: Send + Sync + 'static {
+    fn call(&self, frame: Frame, reply: ReplySender) -> BoxFuture<'_>;
+}
+
+impl<H: FrameHandler> ErasedHandler for H {
+    fn call(&self, frame: Frame, reply: ReplySender) -> BoxFuture<'_> {
+        Box::pin(self.handle(frame, reply))
+    }
+}
+
+/// What a [`Router`] does with a frame whose `KIND` has no route and no fallback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownKindPolicy {
+    /// Discard the frame and keep serving the stream.
+    #[default]
+    Drop,
+    /// Fail with [`Nwd1QuicError::UnknownKind`], which makes an [`Nwd1Server`](crate::Nwd1Server)
+    /// reset the stream with [`HANDLER_ERROR_CODE`](crate::HANDLER_ERROR_CODE).
+    Error,
+    /// Reset the reply stream with the given code, then fail like [`Error`](Self::Error).
+    Reset(u32),
+}
+
+/// Routes frames to handlers by `KIND`.
+///
+/// A `KIND` covered by several routes goes to the one added last. Frames no route covers go to
+/// the [`fallback`](Self::fallback) handler, or are treated according to the
+/// [`UnknownKindPolicy`] without one. A router is itself a [`FrameHandler`], so it can be
+/// served by an [`Nwd1Server`](crate::Nwd1Server) or nested in another router.
+#[derive(Default)]
+pub struct Router {
+    handlers: Vec<Arc<dyn ErasedHandler>>,
+    table: Vec<Option<usize>>,
+    fallback: Option<Arc<dyn ErasedHandler>>,
+    unknown: UnknownKindPolicy,
+}
+
+impl Router {
+    /// An empty router, dropping every frame.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Route frames of `kind` to `handler`.
+    pub fn route(self, kind: u8, handler: impl FrameHandler) -> Self {
+        self.route_range(kind..=kind, handler)
+    }
+
+    /// Route frames with a `KIND` in `kinds` to `handler`.
+    pub fn route_range(mut self, kinds: RangeInclusive<u8>, handler: impl FrameHandler) -> Self {
+        if self.table.is_empty() {
+            self.table = vec![None; 256];
+        }
+        let index = self.handlers.len();
+        self.handlers.push(Arc::new(handler));
+        for kind in kinds {
+            self.table[kind as usize] = Some(index);
+        }
+        self
+    }
+
+    /// Handle frames no route covers with `handler`.
+    pub fn fallback(mut self, handler: impl FrameHandler) -> Self {
+        self.fallback = Some(Arc::new(handler));
+        self
+    }
+
+    /// Set what happens to frames no route covers when there is no fallback.
+    pub fn unknown_kind(mut self, policy: UnknownKindPolicy) -> Self {
+        self.unknown = policy;
+        self
+    }
+
+    /// Whether a frame of `kind` reaches a route or the fallback.
+    pub fn handles(&self, kind: u8) -> bool {
+        self.lookup(kind).is_some()
+    }
+
+    fn lookup(&self, kind: u8) -> Option<&dyn ErasedHandler> {
+        match self.table.get(kind as usize).copied().flatten() {
+            Some(index) => Some(&*self.handlers[index]),
+            None => self.fallback.as_deref(),
+        }
+    }
+
+    /// Dispatch `frame` to its handler.
+    pub async fn dispatch(&self, frame: Frame, reply: ReplySender) -> Result<(), Nwd1QuicError> {
+        if let Some(handler) = self.lookup(frame.kind) {
+            return handler.call(frame, reply).await;
+        }
+        let kind = frame.kind;
+        match self.unknown {
+            UnknownKindPolicy::Drop => Ok(()),
+            UnknownKindPolicy::Error => Err(Nwd1QuicError::UnknownKind { kind }),
+            UnknownKindPolicy::Reset(code) => {
+                // Already finished or reset by another handler otherwise
+                let _ = reply.reset(code).await;
+                Err(Nwd1QuicError::UnknownKind { kind })
+            }
+        }
+    }
+}
+
+impl FrameHandler for Router {
+    fn handle(
+        &self,
+        frame: Frame,
+        reply: ReplySender,
+    ) -> impl Future<Output = Result<(), Nwd1QuicError>> + Send {
+        self.dispatch(frame, reply)
+    }
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kinds: Vec<u8> = (0..=u8::MAX)
+            .filter(|&kind| self.table.get(kind as usize).copied().flatten().is_some())
+            .collect();
+        f.debug_struct("Router")
+            .field("kinds", &kinds)
+            .field("fallback", &self.fallback.is_some())
+            .field("unknown", &self.unknown)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use quinn::{ReadError, VarInt};
+
+    use super::*;
+    use crate::test_util::{frame, start_server};
+    use crate::{ServerLimits, recv_frame, send_frame};
+
+    /// A handler answering with `tag` as payload.
+    fn tagged(tag: &'static str) -> impl FrameHandler {
+        move |mut frame: Frame, reply: ReplySender| async move {
+            frame.payload = Bytes::from_static(tag.as_bytes());
+            reply.send(&frame).await
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_by_kind_and_range() {
+        let router = Router::new()
+            .route_range(10..=19, tagged("range"))
+            .route(12, tagged("twelve"))
+            .fallback(tagged("fallback"));
+        assert!(router.handles(0));
+        let running = start_server(ServerLimits::default(), router).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        for kind in [12, 15, 200] {
+            send_frame(&mut send, &frame(1, kind, Bytes::new())).await.unwrap();
+        }
+        send.finish().unwrap();
+
+        for (kind, tag) in [(12, "twelve"), (15, "range"), (200, "fallback")] {
+            let reply = recv_frame(&mut recv).await.unwrap().unwrap();
+            assert_eq!((reply.kind, &reply.payload[..]), (kind, tag.as_bytes()));
+        }
+        running.shutdown.cancel();
+    }
+
+    #[tokio::test]
+    async fn applies_unknown_kind_policy() {
+        let router = Router::new().route(1, tagged("one"));
+        assert!(!router.handles(2));
+        let running = start_server(ServerLimits::default(), router).await;
+
+        // Dropped by default, the stream keeps serving
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        send_frame(&mut send, &frame(1, 2, Bytes::new())).await.unwrap();
+        send_frame(&mut send, &frame(1, 1, Bytes::new())).await.unwrap();
+        assert_eq!(recv_frame(&mut recv).await.unwrap().unwrap().kind, 1);
+        running.shutdown.cancel();
+
+        let router =
+            Router::new().route(1, tagged("one")).unknown_kind(UnknownKindPolicy::Reset(7));
+        let running = start_server(ServerLimits::default(), router).await;
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        send_frame(&mut send, &frame(1, 2, Bytes::new())).await.unwrap();
+        let Err(err) = recv_frame(&mut recv).await else { panic!("stream not reset") };
+        assert!(
+            matches!(err, Nwd1QuicError::Read(ReadError::Reset(code)) if code == VarInt::from_u32(7))
+        );
+        running.shutdown.cancel();
+    }
+}
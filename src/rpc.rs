@@ -0,0 +1,367 @@
+//! Request/response calls over a shared bidirectional stream, correlated by frame `ID`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::Bytes;
+use netid64::NetId64;
+use nwd1::Frame;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+
+use crate::call::{KIND_CANCEL, terminal_frame};
+use crate::{
+    FrameReceiver, FrameSender, Nwd1QuicError, TraceContext, current_deadline,
+    current_trace_context, deadline, trace,
+};
+
+/// Requests queued for the writer before [`RpcClient::call`] waits.
+const REQUEST_BACKLOG: usize = 64;
+
+/// Sends requests on one stream and matches responses to them by `id.raw()`.
+///
+/// The peer answers a request with a frame carrying the same `ID`, in any order; responses
+/// without a pending request are discarded. Cloning is cheap and clones share the stream. The
+/// stream is abandoned once the last clone is dropped.
+#[derive(Clone)]
+pub struct RpcClient {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    requests: mpsc::Sender<Request>,
+    pending: Arc<Mutex<Pending>>,
+    // Dropping the set aborts the writer and reader
+    _tasks: JoinSet<()>,
+}
+
+/// A request and the scope of the call that sent it, which the writer sends it in.
+struct Request {
+    frame: Frame,
+    deadline: Option<Instant>,
+    trace: Option<TraceContext>,
+}
+
+impl Request {
+    fn unscoped(frame: Frame) -> Self {
+        Self { frame, deadline: None, trace: None }
+    }
+}
+
+type Reply = oneshot::Sender<Result<Frame, Nwd1QuicError>>;
+
+#[derive(Default)]
+struct Pending {
+    calls: HashMap<u64, (u64, Reply)>,
+    next_token: u64,
+    /// The error that ended the stream.
+    closed: Option<Nwd1QuicError>,
+}
+
+impl Pending {
+    /// Fail every pending call with `error`, and new ones likewise.
+    fn close(&mut self, error: Nwd1QuicError) {
+        for (_, (_, tx)) in self.calls.drain() {
+            let _ = tx.send(Err(error.duplicate()));
+        }
+        self.closed = Some(error);
+    }
+
+    /// Fail the call waiting for `id`, if any.
+    fn fail(&mut self, id: u64, error: Nwd1QuicError) {
+        if let Some((_, tx)) = self.calls.remove(&id) {
+            let _ = tx.send(Err(error));
+        }
+    }
+}
+
+/// Removes a call's pending entry when the call completes, times out or is dropped.
+///
+/// A call still pending at that point was abandoned, so the peer is sent a
+/// [`KIND_CANCEL`](crate::KIND_CANCEL) frame for it.
+struct PendingGuard<'a> {
+    inner: &'a Inner,
+    id: u64,
+    token: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        let mut pending = self.inner.pending.lock().unwrap();
+        // Answered or failed already, or the entry belongs to a later call reusing the id
+        if pending.calls.get(&self.id).is_none_or(|(token, _)| *token != self.token) {
+            return;
+        }
+        pending.calls.remove(&self.id);
+        drop(pending);
+
+        let cancel = terminal_frame(NetId64::from_raw(self.id), KIND_CANCEL, Bytes::new());
+        let cancel = Request::unscoped(cancel);
+        if let Err(TrySendError::Full(cancel)) = self.inner.requests.try_send(cancel) {
+            // Without a runtime the cancel is skipped and the late response discarded
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                let requests = self.inner.requests.clone();
+                handle.spawn(async move { requests.send(cancel).await });
+            }
+        }
+    }
+}
+
+impl RpcClient {
+    /// Run calls over a channel, e.g. from [`Nwd1Client::open_channel`](crate::Nwd1Client::open_channel).
+    pub fn new(sender: FrameSender, receiver: FrameReceiver) -> Self {
+        let (requests, rx) = mpsc::channel(REQUEST_BACKLOG);
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let mut tasks = JoinSet::new();
+        tasks.spawn(write_requests(sender, rx, pending.clone()));
+        tasks.spawn(read_responses(receiver, pending.clone()));
+        Self { inner: Arc::new(Inner { requests, pending, _tasks: tasks }) }
+    }
+
+    /// Send `request` and wait for the frame answering it.
+    ///
+    /// Fails with [`Nwd1QuicError::DuplicateRequestId`] if a call with the same `ID` is still
+    /// pending, with the error of the sender's interceptors if they reject the request, and
+    /// with the error that ended the stream if it fails first, [`Nwd1QuicError::Closed`] if the
+    /// peer finished it. Responses the receiver refuses are skipped.
+    ///
+    /// A [deadline](crate::with_deadline) or [trace context](crate::with_trace_context) in
+    /// scope is carried by the request. Dropping the future cancels the call: the peer is sent
+    /// a [`KIND_CANCEL`](crate::KIND_CANCEL) frame with the request's `ID`, and a late response
+    /// is discarded.
+    pub async fn call(&self, request: Frame) -> Result<Frame, Nwd1QuicError> {
+        let id = request.id.raw();
+        // The writer task runs outside the caller's scope, so it is handed over
+        let request = Request {
+            frame: request,
+            deadline: current_deadline(),
+            trace: current_trace_context(),
+        };
+        let (tx, rx) = oneshot::channel();
+        let token = {
+            let mut pending = self.inner.pending.lock().unwrap();
+            if let Some(error) = &pending.closed {
+                return Err(error.duplicate());
+            }
+            if pending.calls.contains_key(&id) {
+                return Err(Nwd1QuicError::DuplicateRequestId { id });
+            }
+            let token = pending.next_token;
+            pending.next_token += 1;
+            pending.calls.insert(id, (token, tx));
+            token
+        };
+        let _guard = PendingGuard { inner: &self.inner, id, token };
+
+        // Should the writer be gone, the error that stopped it was sent to `rx`
+        let _ = self.inner.requests.send(request).await;
+        rx.await.unwrap_or(Err(Nwd1QuicError::Closed))
+    }
+
+    /// Like [`call`](Self::call), failing with [`Nwd1QuicError::Timeout`] if no response
+    /// arrives within `timeout`.
+    pub async fn call_timeout(
+        &self,
+        request: Frame,
+        timeout: Duration,
+    ) -> Result<Frame, Nwd1QuicError> {
+        tokio::time::timeout(timeout, self.call(request))
+            .await
+            .map_err(|_| Nwd1QuicError::Timeout)?
+    }
+
+    /// Number of calls waiting for a response.
+    pub fn pending(&self) -> usize {
+        self.inner.pending.lock().unwrap().calls.len()
+    }
+}
+
+impl fmt::Debug for RpcClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcClient").field("pending", &self.pending()).finish_non_exhaustive()
+    }
+}
+
+async fn write_requests(
+    mut sender: FrameSender,
+    mut requests: mpsc::Receiver<Request>,
+    pending: Arc<Mutex<Pending>>,
+) {
+    // Requests are written here rather than in `call`, so a cancelled call never leaves half
+    // a frame on the shared stream
+    while let Some(Request { frame, deadline, trace }) = requests.recv().await {
+        let send = trace::scoped(trace, sender.send(&frame));
+        match deadline::scoped(deadline, send).await {
+            Ok(()) => {}
+            Err(e @ (Nwd1QuicError::Write(_) | Nwd1QuicError::ConnectionLost(_))) => {
+                pending.lock().unwrap().close(e);
+                return;
+            }
+            // Refused before anything was written, so the stream is intact
+            Err(_) if frame.kind == KIND_CANCEL => {}
+            Err(e) => pending.lock().unwrap().fail(frame.id.raw(), e),
+        }
+    }
+    let _ = sender.finish();
+}
+
+async fn read_responses(mut receiver: FrameReceiver, pending: Arc<Mutex<Pending>>) {
+    let error = loop {
+        match receiver.recv_outcome().await {
+            Ok(Some(Ok(response))) => {
+                let call = pending.lock().unwrap().calls.remove(&response.id.raw());
+                if let Some((_, tx)) = call {
+                    let _ = tx.send(Ok(response));
+                }
+            }
+            // A single frame was refused; the responses after it still arrive
+            Ok(Some(Err(_))) => {}
+            Ok(None) => break Nwd1QuicError::Closed,
+            Err(e) => break e,
+        }
+    };
+    pending.lock().unwrap().close(error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{configs, frame, localhost};
+    use crate::{
+        HANDLER_ERROR_CODE, Intercept, Interceptor, InterceptorStack, Nwd1Client, Nwd1Server,
+        ReplySender, ServerLimits, with_deadline,
+    };
+
+    /// Echoes requests after `kind` times 10 ms, never answering kind 0, finishing the stream
+    /// on kind 250 and failing on kind 251.
+    async fn start() -> (Nwd1Client, RpcClient) {
+        let (server_config, client_config) = configs();
+        let mut server = Nwd1Server::bind(localhost(), server_config).unwrap();
+        server.set_limits(ServerLimits { max_in_flight_per_stream: 8, ..ServerLimits::default() });
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(server.serve(|frame: Frame, reply: ReplySender| async move {
+            match frame.kind {
+                0 => Ok(()),
+                250 => reply.finish().await,
+                251 => Err(Nwd1QuicError::Remote { message: "boom".into() }),
+                kind => {
+                    tokio::time::sleep(Duration::from_millis(10 * kind as u64)).await;
+                    reply.send(&frame).await
+                }
+            }
+        }));
+
+        let client = Nwd1Client::connect(addr, "localhost", client_config).await.unwrap();
+        let (tx, rx) = client.open_channel().await.unwrap();
+        (client, RpcClient::new(tx, rx))
+    }
+
+    #[tokio::test]
+    async fn matches_out_of_order_responses() {
+        let (_client, rpc) = start().await;
+        let (slow, fast) = tokio::join!(rpc.call(frame(1, 5, "req")), rpc.call(frame(2, 1, "req")));
+        assert_eq!(slow.unwrap().id.counter(), 1);
+        assert_eq!(fast.unwrap().id.counter(), 2);
+        assert_eq!(rpc.pending(), 0);
+    }
+
+    #[tokio::test]
+    async fn timeouts_and_duplicates_clean_up() {
+        let (_client, rpc) = start().await;
+
+        let Err(err) = rpc.call_timeout(frame(1, 0, "req"), Duration::from_millis(20)).await else {
+            panic!("unanswered call completed");
+        };
+        assert!(matches!(err, Nwd1QuicError::Timeout));
+        assert_eq!(rpc.pending(), 0);
+
+        let first = rpc.call(frame(2, 3, "req"));
+        tokio::pin!(first);
+        // Poll once so the first call is registered
+        assert!(futures::poll!(first.as_mut()).is_pending());
+        let Err(err) = rpc.call(frame(2, 1, "req")).await else { panic!("duplicate id accepted") };
+        assert!(matches!(err, Nwd1QuicError::DuplicateRequestId { .. }));
+        assert_eq!(first.await.unwrap().id.counter(), 2);
+    }
+
+    #[tokio::test]
+    async fn intercepts_requests_before_deadline_applies() {
+        /// Reverses sent payloads, which would garble a deadline prefix already in place.
+        struct Reverse;
+
+        impl Interceptor for Reverse {
+            fn on_send(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+                frame.payload = frame.payload.iter().rev().copied().collect::<Vec<_>>().into();
+                Ok(Intercept::Pass)
+            }
+        }
+
+        let (client, _) = start().await;
+        let (mut tx, rx) = client.open_channel().await.unwrap();
+        tx.set_interceptors(InterceptorStack::new().with(Reverse));
+        let rpc = RpcClient::new(tx, rx);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let response = with_deadline(deadline, rpc.call(frame(1, 1, "req"))).await.unwrap();
+        assert_eq!((response.ver, &response.payload[..]), (1, &b"qer"[..]));
+    }
+
+    #[tokio::test]
+    async fn rejected_frames_fail_alone() {
+        /// Rejects frames of `KIND` 2 in either direction.
+        struct Gate;
+
+        impl Interceptor for Gate {
+            fn on_send(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+                self.on_recv(frame)
+            }
+
+            fn on_recv(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+                match frame.kind {
+                    2 => Err(Nwd1QuicError::Rejected("kind 2".into())),
+                    _ => Ok(Intercept::Pass),
+                }
+            }
+        }
+
+        let (client, _) = start().await;
+        let (mut tx, rx) = client.open_channel().await.unwrap();
+        tx.set_interceptors(InterceptorStack::new().with(Gate));
+        let rpc = RpcClient::new(tx, rx);
+        let Err(err) = rpc.call(frame(1, 2, "req")).await else { panic!("rejected request sent") };
+        assert!(matches!(err, Nwd1QuicError::Rejected(_)));
+        assert_eq!(rpc.call(frame(2, 1, "req")).await.unwrap().id.counter(), 2);
+
+        let (tx, mut rx) = client.open_channel().await.unwrap();
+        rx.set_interceptors(InterceptorStack::new().with(Gate));
+        let rpc = RpcClient::new(tx, rx);
+        let refused = rpc.call_timeout(frame(1, 2, "req"), Duration::from_millis(100)).await;
+        assert!(matches!(refused, Err(Nwd1QuicError::Timeout)));
+        assert_eq!(rpc.call(frame(2, 1, "req")).await.unwrap().id.counter(), 2);
+    }
+
+    #[tokio::test]
+    async fn fails_pending_calls_with_stream_error() {
+        let (_client, rpc) = start().await;
+        let pending = rpc.call(frame(1, 0, "req"));
+        let (pending, failed) = tokio::join!(pending, rpc.call(frame(2, 251, "req")));
+
+        let reset = |result: Result<Frame, Nwd1QuicError>| matches!(result, Err(Nwd1QuicError::Read(quinn::ReadError::Reset(code))) if code == HANDLER_ERROR_CODE.into());
+        assert!(reset(pending) && reset(failed));
+        assert!(reset(rpc.call(frame(3, 1, "req")).await));
+    }
+
+    #[tokio::test]
+    async fn fails_pending_calls_when_stream_ends() {
+        let (_client, rpc) = start().await;
+        let pending = rpc.call(frame(1, 0, "req"));
+        let (pending, _) = tokio::join!(pending, rpc.call(frame(2, 250, "req")));
+
+        assert!(matches!(pending, Err(Nwd1QuicError::Closed)));
+        assert!(matches!(rpc.call(frame(3, 1, "req")).await, Err(Nwd1QuicError::Closed)));
+    }
+}
@@ -0,0 +1,674 @@
+//! A ready-made server: endpoint, accept loops and per-frame dispatch to a [`FrameHandler`].
+
+use std::collections::{BTreeMap, VecDeque};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use netid64::NetId64;
+use nwd1::Frame;
+use quinn::{Connection, Endpoint, RecvStream, SendStream, WriteError};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::call::{KIND_CANCEL, KIND_END, KIND_ERROR, terminal_frame};
+use crate::interceptor::clone_frame;
+use crate::{
+    FrameLimits, FrameReader, InterceptorStack, Nwd1QuicError, TraceContext, deadline, send_frame,
+    trace,
+};
+
+/// Error code a server stops or resets a stream with after a malformed frame.
+pub const PROTOCOL_ERROR_CODE: u32 = 1;
+/// Error code a server resets a reply stream with when its handler fails.
+pub const HANDLER_ERROR_CODE: u32 = 2;
+
+/// Frames read from a stream beyond its free in-flight slots, so a CANCEL is seen while the
+/// request it cancels still runs.
+const READ_AHEAD: usize = 1;
+
+/// Handles the frames an [`Nwd1Server`] receives.
+///
+/// `handle` is called once per frame with a [`ReplySender`] for the stream the frame arrived
+/// on. Returning an error resets the reply stream with [`HANDLER_ERROR_CODE`], stops reading
+/// from it and cancels the other calls still running for it; other streams are unaffected.
+/// [`Nwd1QuicError::Cancelled`], returned once the peer cancelled the call, is not treated as
+/// an error.
+///
+/// Frames carrying a [deadline](crate::DEADLINE_FLAG) are not handed to `handle` once it has
+/// passed; the deadline is stripped from the payload and available as
+/// [`ReplySender::deadline`]. Each call runs in a `handle` span, continuing the trace of a
+/// [`TRACEPARENT`](crate::TRACEPARENT) header its frame carries; the header is removed likewise.
+///
+/// Implemented for closures `Fn(Frame, ReplySender) -> impl Future<Output = Result<(), _>>`.
+pub trait FrameHandler: Send + Sync + 'static {
+    /// Handle one frame received on the stream `reply` answers on.
+    fn handle(
+        &self,
+        frame: Frame,
+        reply: ReplySender,
+    ) -> impl Future<Output = Result<(), Nwd1QuicError>> + Send;
+}
+
+impl<F, Fut> FrameHandler for F
+where
+    F: Fn(Frame, ReplySender) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), Nwd1QuicError>> + Send,
+{
+    fn handle(
+        &self,
+        frame: Frame,
+        reply: ReplySender,
+    ) -> impl Future<Output = Result<(), Nwd1QuicError>> + Send {
+        self(frame, reply)
+    }
+}
+
+/// The send half of the stream a frame arrived on, shared by every handler call for that stream.
+///
+/// Each handler call gets its own clone, tied to that call's cancellation: a
+/// [`KIND_CANCEL`](crate::KIND_CANCEL) frame with the request's `ID` cancels it, which
+/// handlers observe through [`cancelled`](Self::cancelled) and which makes further sends fail.
+///
+/// Cloning is cheap. The stream is finished automatically once the peer finishes its side and
+/// every handler call has returned, unless a handler finished or reset it first.
+#[derive(Debug, Clone)]
+pub struct ReplySender {
+    stream: Arc<Mutex<SendStream>>,
+    conn: Connection,
+    interceptors: InterceptorStack,
+    cancel: CancellationToken,
+    deadline: Option<Instant>,
+}
+
+impl ReplySender {
+    /// Send a frame on the reply stream, through the server's interceptors.
+    ///
+    /// Fails with [`Nwd1QuicError::Cancelled`] once the call is cancelled.
+    pub async fn send(&self, frame: &Frame) -> Result<(), Nwd1QuicError> {
+        if self.cancel.is_cancelled() {
+            return Err(Nwd1QuicError::Cancelled);
+        }
+        if self.interceptors.is_empty() {
+            return send_frame(&mut *self.stream.lock().await, frame).await;
+        }
+        match self.interceptors.on_send(clone_frame(frame))? {
+            Some(frame) => send_frame(&mut *self.stream.lock().await, &frame).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Finish the reply stream; no more frames can be sent on it.
+    pub async fn finish(&self) -> Result<(), Nwd1QuicError> {
+        self.stream.lock().await.finish().map_err(|e| WriteError::from(e).into())
+    }
+
+    /// End the streaming call `id` after its last response, sending
+    /// [`KIND_END`](crate::KIND_END).
+    pub async fn end(&self, id: NetId64) -> Result<(), Nwd1QuicError> {
+        self.send(&terminal_frame(id, KIND_END, Bytes::new())).await
+    }
+
+    /// Abort the streaming call `id` with `message`, sending [`KIND_ERROR`](crate::KIND_ERROR).
+    pub async fn fail(&self, id: NetId64, message: &str) -> Result<(), Nwd1QuicError> {
+        let payload = Bytes::copy_from_slice(message.as_bytes());
+        self.send(&terminal_frame(id, KIND_ERROR, payload)).await
+    }
+
+    /// Abandon the reply stream, signalling `code` to the peer.
+    pub async fn reset(&self, code: u32) -> Result<(), Nwd1QuicError> {
+        self.stream.lock().await.reset(code.into()).map_err(|e| WriteError::from(e).into())
+    }
+
+    /// Whether the peer cancelled the call this reply belongs to.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Wait until the peer cancels the call this reply belongs to.
+    pub async fn cancelled(&self) {
+        self.cancel.cancelled().await
+    }
+
+    /// A token cancelled together with the call, for work spawned by the handler.
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// The deadline the frame arrived with, if any.
+    ///
+    /// The handler also runs with it in scope, see [`with_deadline`](crate::with_deadline).
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The connection the frame arrived on.
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+}
+
+/// Concurrency limits of an [`Nwd1Server`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerLimits {
+    /// Connections served at once; further ones are refused. Defaults to 1024.
+    pub max_connections: usize,
+    /// Bidirectional streams served at once per connection; further ones wait to be accepted.
+    /// Defaults to 100.
+    pub max_streams_per_connection: usize,
+    /// Handler calls running at once per stream. The default of 1 handles the frames of a
+    /// stream strictly in order.
+    pub max_in_flight_per_stream: usize,
+    /// Limits applied to every received frame.
+    pub frame: FrameLimits,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_streams_per_connection: 100,
+            max_in_flight_per_stream: 1,
+            frame: FrameLimits::default(),
+        }
+    }
+}
+
+/// Accepts connections and bidirectional streams, dispatching every received frame to a
+/// [`FrameHandler`].
+///
+/// Cancelling the [`shutdown_token`](Self::shutdown_token) shuts down gracefully: no new
+/// connections, streams or frames are accepted, handler calls in progress and frames already
+/// read run to completion and their replies are delivered, then the endpoint is closed.
+#[derive(Debug)]
+pub struct Nwd1Server {
+    endpoint: Endpoint,
+    limits: ServerLimits,
+    interceptors: InterceptorStack,
+    shutdown: CancellationToken,
+}
+
+impl Nwd1Server {
+    /// Bind a server endpoint to `addr`.
+    pub fn bind(addr: SocketAddr, config: quinn::ServerConfig) -> Result<Self, Nwd1QuicError> {
+        Ok(Self::from_endpoint(Endpoint::server(config, addr)?))
+    }
+
+    /// Serve on an existing endpoint, which must have a server config.
+    pub fn from_endpoint(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            limits: ServerLimits::default(),
+            interceptors: InterceptorStack::new(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// Replace the concurrency and frame limits.
+    pub fn set_limits(&mut self, limits: ServerLimits) {
+        self.limits = limits;
+    }
+
+    /// Run every received frame and every reply through `interceptors`.
+    ///
+    /// Received frames they drop never reach the handler; one they reject is treated like a
+    /// malformed frame.
+    pub fn set_interceptors(&mut self, interceptors: InterceptorStack) {
+        self.interceptors = interceptors;
+    }
+
+    /// The address the endpoint is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr, Nwd1QuicError> {
+        Ok(self.endpoint.local_addr()?)
+    }
+
+    /// A token that shuts the server down when cancelled.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Serve until shut down, returning once every connection has drained.
+    pub async fn serve<H: FrameHandler>(self, handler: H) -> Result<(), Nwd1QuicError> {
+        let Self { endpoint, limits, interceptors, shutdown } = self;
+        let handler = Arc::new(handler);
+        let limits = Arc::new(limits);
+        let slots = Arc::new(Semaphore::new(limits.max_connections));
+        let mut connections = JoinSet::new();
+
+        loop {
+            tokio::select! {
+                () = shutdown.cancelled() => break,
+                incoming = endpoint.accept() => {
+                    let Some(incoming) = incoming else { break };
+                    let Ok(slot) = slots.clone().try_acquire_owned() else {
+                        incoming.refuse();
+                        continue;
+                    };
+                    let (handler, limits, interceptors, shutdown) =
+                        (handler.clone(), limits.clone(), interceptors.clone(), shutdown.clone());
+                    connections.spawn(async move {
+                        if let Ok(conn) = async { incoming.accept()?.await }.await {
+                            serve_connection(conn, handler, limits, interceptors, shutdown).await;
+                        }
+                        drop(slot);
+                    });
+                }
+                Some(_) = connections.join_next() => {}
+            }
+        }
+
+        // Shut down gracefully even if the endpoint stopped on its own
+        shutdown.cancel();
+        while connections.join_next().await.is_some() {}
+        endpoint.close(0u32.into(), b"shutdown");
+        endpoint.wait_idle().await;
+        Ok(())
+    }
+}
+
+async fn serve_connection<H: FrameHandler>(
+    conn: Connection,
+    handler: Arc<H>,
+    limits: Arc<ServerLimits>,
+    interceptors: InterceptorStack,
+    shutdown: CancellationToken,
+) {
+    let slots = Arc::new(Semaphore::new(limits.max_streams_per_connection));
+    let mut streams = JoinSet::new();
+
+    loop {
+        let accepted = tokio::select! {
+            () = shutdown.cancelled() => break,
+            Some(_) = streams.join_next() => continue,
+            accepted = async {
+                let slot = slots.clone().acquire_owned().await.expect("semaphore never closed");
+                (conn.accept_bi().await, slot)
+            } => accepted,
+        };
+        let ((send, recv), slot) = match accepted {
+            (Ok(streams), slot) => (streams, slot),
+            (Err(_), _) => break,
+        };
+        let reply = ReplySender {
+            stream: Arc::new(Mutex::new(send)),
+            conn: conn.clone(),
+            interceptors: interceptors.clone(),
+            cancel: CancellationToken::new(),
+            deadline: None,
+        };
+        let (handler, limits, shutdown) = (handler.clone(), limits.clone(), shutdown.clone());
+        streams.spawn(async move {
+            serve_stream(recv, reply, handler, &limits, shutdown).await;
+            drop(slot);
+        });
+    }
+
+    while streams.join_next().await.is_some() {}
+    if shutdown.is_cancelled() {
+        conn.close(0u32.into(), b"shutdown");
+    }
+}
+
+async fn serve_stream<H: FrameHandler>(
+    recv: RecvStream,
+    reply: ReplySender,
+    handler: Arc<H>,
+    limits: &ServerLimits,
+    shutdown: CancellationToken,
+) {
+    let mut reader = FrameReader::new(recv);
+    reader.set_limits(limits.frame.clone());
+    let in_flight = Arc::new(Semaphore::new(limits.max_in_flight_per_stream.max(1)));
+    let mut calls = JoinSet::new();
+    // Frames are read ahead of the handlers, so a CANCEL is seen while its request still runs
+    let mut queued = VecDeque::new();
+    // Keyed by `ID` and a sequence number, as calls on a stream may share an `ID`
+    let mut running: BTreeMap<(u64, u64), CancellationToken> = BTreeMap::new();
+    let mut seq = 0u64;
+    let mut reading = true;
+    let mut failed = false;
+
+    while !failed && (reading || !queued.is_empty() || !calls.is_empty()) {
+        tokio::select! {
+            // Prefer reaping finished calls so a failed handler stops the stream promptly
+            biased;
+            Some(result) = calls.join_next() => {
+                failed = match result {
+                    Ok((key, result)) => {
+                        running.remove(&key);
+                        !matches!(result, Ok(()) | Err(Nwd1QuicError::Cancelled))
+                    }
+                    Err(_) => true,
+                };
+            }
+            // Frames already read are still handled
+            () = shutdown.cancelled(), if reading => reading = false,
+            permit = in_flight.clone().acquire_owned(), if !queued.is_empty() => {
+                let permit = permit.expect("semaphore never closed");
+                let (frame, deadline, parent): (Frame, _, Option<TraceContext>) =
+                    queued.pop_front().expect("queue not empty");
+                // The caller has given up on frames whose deadline passed while queued
+                if deadline::expired(deadline) {
+                    continue;
+                }
+                let key = (frame.id.raw(), seq);
+                seq += 1;
+                let cancel = CancellationToken::new();
+                let reply = ReplySender { cancel: cancel.clone(), deadline, ..reply.clone() };
+                running.insert(key, cancel);
+                let span = trace::handle_span(&frame, parent.as_ref());
+                let trace = parent.map(|parent| parent.child());
+                let handler = handler.clone();
+                calls.spawn(async move {
+                    let call = trace::scoped(trace, handler.handle(frame, reply));
+                    let result = deadline::scoped(deadline, call).instrument(span).await;
+                    drop(permit);
+                    (key, result)
+                });
+            }
+            frame = reader.next_frame(),
+                if reading && queued.len() < in_flight.available_permits() + READ_AHEAD =>
+            match frame {
+                Ok(Some(mut frame)) => {
+                    let received = deadline::arrive(&mut frame).and_then(|deadline| {
+                        let parent = trace::arrive(&mut frame);
+                        Ok(reply.interceptors.on_recv(frame)?.map(|frame| (frame, deadline, parent)))
+                    });
+                    let (frame, deadline, parent) = match received {
+                        Ok(Some(received)) => received,
+                        Ok(None) => continue,
+                        Err(_) => {
+                            let _ = reader.get_mut().stop(PROTOCOL_ERROR_CODE.into());
+                            let _ = reply.reset(PROTOCOL_ERROR_CODE).await;
+                            return;
+                        }
+                    };
+                    if frame.kind != KIND_CANCEL {
+                        queued.push_back((frame, deadline, parent));
+                        continue;
+                    }
+                    let id = frame.id.raw();
+                    queued.retain(|(queued, ..)| queued.id.raw() != id);
+                    running.range((id, 0)..=(id, u64::MAX)).for_each(|(_, cancel)| cancel.cancel());
+                }
+                Ok(None) => reading = false,
+                Err(e) => {
+                    if e.is_protocol_error() {
+                        let _ = reader.get_mut().stop(PROTOCOL_ERROR_CODE.into());
+                        let _ = reply.reset(PROTOCOL_ERROR_CODE).await;
+                    }
+                    return;
+                }
+            },
+        }
+    }
+
+    if failed {
+        // Their replies could no longer be sent
+        running.values().for_each(CancellationToken::cancel);
+    }
+    while let Some(result) = calls.join_next().await {
+        failed |= !matches!(result, Ok((_, Ok(()) | Err(Nwd1QuicError::Cancelled))));
+    }
+
+    let mut stream = reply.stream.lock().await;
+    if failed {
+        let _ = stream.reset(HANDLER_ERROR_CODE.into());
+        let _ = reader.get_mut().stop(HANDLER_ERROR_CODE.into());
+        return;
+    }
+    // Already finished or reset by a handler otherwise
+    if stream.finish().is_ok() {
+        let stopped = stream.stopped();
+        drop(stream);
+        // Wait for the peer to acknowledge the replies, so closing the connection on shutdown
+        // does not discard them
+        let _ = stopped.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use netid64::NetId64;
+
+    use super::*;
+    use crate::test_util::{frame, start_server, start_server_with};
+    use crate::{
+        FrameReceiver, FrameSender, Intercept, Interceptor, RpcClient, current_deadline,
+        recv_frame, set_deadline,
+    };
+
+    #[tokio::test]
+    async fn echoes_frames_until_shutdown() {
+        let echo = |frame: Frame, reply: ReplySender| async move { reply.send(&frame).await };
+        let running = start_server(ServerLimits::default(), echo).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        for counter in 0..3 {
+            send_frame(&mut send, &frame(counter, 1, "echo")).await.unwrap();
+        }
+        send.finish().unwrap();
+
+        for counter in 0..3 {
+            let echoed = recv_frame(&mut recv).await.unwrap().unwrap();
+            assert_eq!((echoed.id.counter(), &echoed.payload[..]), (counter, &b"echo"[..]));
+        }
+        // The reply stream is finished once the request stream is
+        assert!(recv_frame(&mut recv).await.unwrap().is_none());
+
+        running.shutdown.cancel();
+        running.serving.await.unwrap().unwrap();
+        assert!(running.conn.closed().await.to_string().contains("shutdown"));
+    }
+
+    /// Reports the counter of every frame the server reads.
+    struct Reads(tokio::sync::mpsc::UnboundedSender<u64>);
+
+    impl Interceptor for Reads {
+        fn on_recv(&self, frame: &mut Frame) -> Result<Intercept, Nwd1QuicError> {
+            self.0.send(frame.id.counter()).unwrap();
+            Ok(Intercept::Pass)
+        }
+    }
+
+    #[tokio::test]
+    async fn handles_frames_read_before_shutdown() {
+        let (reads_tx, mut reads) = tokio::sync::mpsc::unbounded_channel();
+        let (started_tx, mut started) = tokio::sync::mpsc::unbounded_channel();
+        let release = Arc::new(tokio::sync::Notify::new());
+        // Waits to be released before echoing
+        let handler = {
+            let release = release.clone();
+            move |frame: Frame, reply: ReplySender| {
+                let (started_tx, release) = (started_tx.clone(), release.clone());
+                async move {
+                    started_tx.send(frame.id.counter()).unwrap();
+                    release.notified().await;
+                    reply.send(&frame).await
+                }
+            }
+        };
+        let interceptors = InterceptorStack::new().with(Reads(reads_tx));
+        let running = start_server_with(ServerLimits::default(), interceptors, handler).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        for counter in 0..2 {
+            send_frame(&mut send, &frame(counter, 1, "queued")).await.unwrap();
+        }
+        assert_eq!(started.recv().await, Some(0));
+        // Frame 1 is read ahead while frame 0 is handled, then the server shuts down
+        assert_eq!((reads.recv().await, reads.recv().await), (Some(0), Some(1)));
+        running.shutdown.cancel();
+        release.notify_one();
+        assert_eq!(started.recv().await, Some(1));
+        release.notify_one();
+
+        for counter in 0..2 {
+            assert_eq!(recv_frame(&mut recv).await.unwrap().unwrap().id.counter(), counter);
+        }
+        assert!(recv_frame(&mut recv).await.unwrap().is_none());
+        running.serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_in_flight_calls_per_stream() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let handler = {
+            let (running, peak) = (running.clone(), peak.clone());
+            move |frame: Frame, reply: ReplySender| {
+                let (running, peak) = (running.clone(), peak.clone());
+                async move {
+                    peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    reply.send(&frame).await
+                }
+            }
+        };
+
+        let limits = ServerLimits { max_in_flight_per_stream: 2, ..ServerLimits::default() };
+        let running = start_server(limits, handler).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        for counter in 0..6 {
+            send_frame(&mut send, &frame(counter, 1, "slow")).await.unwrap();
+        }
+        send.finish().unwrap();
+        for _ in 0..6 {
+            recv_frame(&mut recv).await.unwrap().unwrap();
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+
+        running.shutdown.cancel();
+        running.serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn resets_stream_on_handler_error() {
+        let (cancelled_tx, mut cancelled) = tokio::sync::mpsc::unbounded_channel();
+        // Kind 1 waits to be cancelled, anything else fails
+        let handler = move |frame: Frame, reply: ReplySender| {
+            let cancelled_tx = cancelled_tx.clone();
+            async move {
+                if frame.kind != 1 {
+                    return Err(Nwd1QuicError::VersionNotAllowed { ver: frame.ver });
+                }
+                reply.cancelled().await;
+                cancelled_tx.send(frame.id.counter()).unwrap();
+                Ok(())
+            }
+        };
+        let limits = ServerLimits { max_in_flight_per_stream: 2, ..ServerLimits::default() };
+        let running = start_server(limits, handler).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        send_frame(&mut send, &frame(0, 1, "wait")).await.unwrap();
+        send_frame(&mut send, &frame(1, 2, "boom")).await.unwrap();
+        let Err(err) = recv_frame(&mut recv).await else { panic!("stream not reset") };
+        assert!(
+            matches!(err, Nwd1QuicError::Read(quinn::ReadError::Reset(code)) if code == HANDLER_ERROR_CODE.into())
+        );
+        assert_eq!(cancelled.recv().await, Some(0));
+
+        running.shutdown.cancel();
+        running.serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancels_running_call_on_cancel_frame() {
+        let (cancelled_tx, mut cancelled) = tokio::sync::mpsc::unbounded_channel();
+        // Kind 1 waits to be cancelled, anything else is echoed
+        let handler = move |frame: Frame, reply: ReplySender| {
+            let cancelled_tx = cancelled_tx.clone();
+            async move {
+                if frame.kind != 1 {
+                    return reply.send(&frame).await;
+                }
+                reply.cancelled().await;
+                cancelled_tx.send(frame.id.counter()).unwrap();
+                reply.send(&frame).await
+            }
+        };
+        // One call in flight at a time, so the CANCEL is only seen through the read-ahead
+        let running = start_server(ServerLimits::default(), handler).await;
+        let (send, recv) = running.conn.open_bi().await.unwrap();
+        let rpc = RpcClient::new(FrameSender::new(send), FrameReceiver::new(recv));
+
+        let Err(err) = rpc.call_timeout(frame(1, 1, "wait"), Duration::from_millis(20)).await
+        else {
+            panic!("cancelled call answered");
+        };
+        assert!(matches!(err, Nwd1QuicError::Timeout));
+        assert_eq!(cancelled.recv().await, Some(1));
+
+        let echo = rpc.call(frame(2, 2, "next")).await.unwrap();
+        assert_eq!(&echo.payload[..], b"next");
+    }
+
+    #[tokio::test]
+    async fn cancels_every_call_sharing_an_id() {
+        let (cancelled_tx, mut cancelled) = tokio::sync::mpsc::unbounded_channel();
+        // Kind 1 waits to be cancelled, anything else is echoed
+        let handler = move |frame: Frame, reply: ReplySender| {
+            let cancelled_tx = cancelled_tx.clone();
+            async move {
+                if frame.kind != 1 {
+                    return reply.send(&frame).await;
+                }
+                reply.cancelled().await;
+                cancelled_tx.send(frame.id.counter()).unwrap();
+                Ok(())
+            }
+        };
+        let limits = ServerLimits { max_in_flight_per_stream: 2, ..ServerLimits::default() };
+        let running = start_server(limits, handler).await;
+
+        // The echo finishes while the waiting call with the same `ID` still runs
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        send_frame(&mut send, &frame(5, 1, "wait")).await.unwrap();
+        send_frame(&mut send, &frame(5, 2, "echo")).await.unwrap();
+        assert_eq!(&recv_frame(&mut recv).await.unwrap().unwrap().payload[..], b"echo");
+
+        let id = NetId64::make(1, 7, 5);
+        send_frame(&mut send, &terminal_frame(id, KIND_CANCEL, Bytes::new())).await.unwrap();
+        assert_eq!(cancelled.recv().await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn drops_expired_frames_and_scopes_deadline() {
+        // Echoes with `KIND` 1 if the frame's deadline is exposed and in scope
+        let handler = |frame: Frame, reply: ReplySender| async move {
+            let scoped = reply.deadline().is_some() && current_deadline() == reply.deadline();
+            reply.send(&Frame { kind: scoped as u8, ..frame }).await
+        };
+        let running = start_server(ServerLimits::default(), handler).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        let mut expired = frame(0, 1, "late");
+        set_deadline(&mut expired, Duration::ZERO);
+        let mut live = frame(1, 1, "live");
+        set_deadline(&mut live, Duration::from_secs(5));
+        for frame in [expired, live, frame(2, 1, "none")] {
+            send_frame(&mut send, &frame).await.unwrap();
+        }
+        send.finish().unwrap();
+
+        let live = recv_frame(&mut recv).await.unwrap().unwrap();
+        assert_eq!(
+            (live.id.counter(), live.kind, live.ver, &live.payload[..]),
+            (1, 1, 1, &b"live"[..])
+        );
+        let plain = recv_frame(&mut recv).await.unwrap().unwrap();
+        assert_eq!((plain.id.counter(), plain.kind), (2, 0));
+        assert!(recv_frame(&mut recv).await.unwrap().is_none());
+    }
+}
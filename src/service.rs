@@ -0,0 +1,207 @@
+//! [`tower::Service`] integration: drive streams through services and serve them from an
+//! [`Nwd1Server`](crate::Nwd1Server).
+//!
+//! A service answers a request frame with `Some(response)`, or with `None` when there is
+//! nothing to send back.
+
+use std::error::Error;
+use std::future::poll_fn;
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use nwd1::Frame;
+use quinn::{RecvStream, SendStream, WriteError};
+use tower::Service;
+use tracing::Instrument;
+
+use crate::{
+    FrameHandler, FrameReader, HANDLER_ERROR_CODE, Nwd1QuicError, ReplySender, deadline,
+    send_frame, trace,
+};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Serve every frame received on `recv` with `service`, writing responses to `send`.
+///
+/// The next frame is only read once `poll_ready` reports capacity, so backpressure from
+/// layers like `ConcurrencyLimit` or `LoadShed` reaches the peer through QUIC flow control.
+/// Calls run concurrently and responses are written as they complete. Frames received after
+/// their [deadline](crate::DEADLINE_FLAG) are skipped; others are called with it and their
+/// [trace context](crate::TraceContext) in scope.
+///
+/// Returns once the peer finished `recv` and every call has completed, finishing `send`. A
+/// service error resets `send` with [`HANDLER_ERROR_CODE`] and is returned as
+/// [`Nwd1QuicError::Service`].
+pub async fn serve_stream<S>(
+    mut send: SendStream,
+    recv: RecvStream,
+    mut service: S,
+) -> Result<(), Nwd1QuicError>
+where
+    S: Service<Frame, Response = Option<Frame>>,
+    S::Error: Into<BoxError>,
+{
+    let mut reader = FrameReader::new(recv);
+    let mut calls = FuturesUnordered::new();
+    let mut reading = true;
+
+    let result = loop {
+        let response = tokio::select! {
+            frame = async {
+                poll_fn(|cx| service.poll_ready(cx)).await.map_err(service_error)?;
+                reader.next_frame().await
+            }, if reading => {
+                match frame {
+                    Ok(Some(mut frame)) => match deadline::arrive(&mut frame) {
+                        Ok(deadline) if deadline::expired(deadline) => {}
+                        Ok(deadline) => {
+                            let parent = trace::arrive(&mut frame);
+                            let span = trace::handle_span(&frame, parent.as_ref());
+                            let call = service.call(frame);
+                            let call = trace::scoped(parent.map(|parent| parent.child()), call);
+                            calls.push(deadline::scoped(deadline, call).instrument(span));
+                        }
+                        Err(e) => break Err(e),
+                    },
+                    Ok(None) => reading = false,
+                    Err(e) => break Err(e),
+                }
+                continue;
+            }
+            Some(response) = calls.next() => response,
+            else => break Ok(()),
+        };
+        match response.map_err(service_error) {
+            Ok(Some(frame)) => {
+                if let Err(e) = send_frame(&mut send, &frame).await {
+                    break Err(e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => break Err(e),
+        }
+    };
+
+    match result {
+        Ok(()) => send.finish().map_err(|e| WriteError::from(e).into()),
+        Err(e) => {
+            let _ = send.reset(HANDLER_ERROR_CODE.into());
+            Err(e)
+        }
+    }
+}
+
+fn service_error(e: impl Into<BoxError>) -> Nwd1QuicError {
+    Nwd1QuicError::Service(e.into())
+}
+
+/// A [`FrameHandler`] calling a [`tower::Service`], so services can be served by an
+/// [`Nwd1Server`](crate::Nwd1Server).
+///
+/// The service is cloned for every frame, as is usual for tower services shared between
+/// tasks; layers keeping shared state (e.g. a concurrency limit) apply across all streams.
+#[derive(Debug, Clone)]
+pub struct ServiceHandler<S> {
+    service: S,
+}
+
+impl<S> ServiceHandler<S> {
+    /// Wrap `service`.
+    pub fn new(service: S) -> Self {
+        Self { service }
+    }
+}
+
+impl<S> FrameHandler for ServiceHandler<S>
+where
+    S: Service<Frame, Response = Option<Frame>> + Clone + Send + Sync + 'static,
+    S::Error: Into<BoxError>,
+    S::Future: Send,
+{
+    async fn handle(&self, frame: Frame, reply: ReplySender) -> Result<(), Nwd1QuicError> {
+        let mut service = self.service.clone();
+        poll_fn(|cx| service.poll_ready(cx)).await.map_err(service_error)?;
+        match service.call(frame).await.map_err(service_error)? {
+            Some(response) => reply.send(&response).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use quinn::{ReadError, VarInt};
+    use tower::ServiceBuilder;
+    use tower::service_fn;
+
+    use super::*;
+    use crate::ServerLimits;
+    use crate::recv_frame;
+    use crate::test_util::{frame, loopback, start_server};
+
+    #[tokio::test]
+    async fn respects_poll_ready() {
+        let lb = loopback().await;
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let service = {
+            let (running, peak) = (running.clone(), peak.clone());
+            service_fn(move |frame: Frame| {
+                let (running, peak) = (running.clone(), peak.clone());
+                async move {
+                    peak.fetch_max(running.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    // Odd frames get no response
+                    Ok::<_, BoxError>(frame.id.counter().is_multiple_of(2).then_some(frame))
+                }
+            })
+        };
+        let service = ServiceBuilder::new().concurrency_limit(2).service(service);
+
+        // A bidirectional stream reaches the peer with its first bytes
+        let (mut send, mut recv) = lb.client.open_bi().await.unwrap();
+        send_frame(&mut send, &frame(0, 1, "x")).await.unwrap();
+        let (server_send, server_recv) = lb.server.accept_bi().await.unwrap();
+        for counter in 1..6 {
+            send_frame(&mut send, &frame(counter, 1, "x")).await.unwrap();
+        }
+        send.finish().unwrap();
+        let serving = tokio::spawn(serve_stream(server_send, server_recv, service));
+
+        let mut counters = Vec::new();
+        while let Some(response) = recv_frame(&mut recv).await.unwrap() {
+            counters.push(response.id.counter());
+        }
+        counters.sort();
+        assert_eq!(counters, [0, 2, 4]);
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        serving.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_services_from_server() {
+        let slow_echo = service_fn(|frame: Frame| async move {
+            tokio::time::sleep(Duration::from_millis(10 * frame.id.counter())).await;
+            Ok::<_, BoxError>(Some(frame))
+        });
+        let service = ServiceBuilder::new().timeout(Duration::from_millis(50)).service(slow_echo);
+        let running = start_server(ServerLimits::default(), ServiceHandler::new(service)).await;
+
+        let (mut send, mut recv) = running.conn.open_bi().await.unwrap();
+        send_frame(&mut send, &frame(1, 1, "x")).await.unwrap();
+        assert_eq!(recv_frame(&mut recv).await.unwrap().unwrap().id.counter(), 1);
+
+        // The timeout layer fails the call, which resets the stream
+        send_frame(&mut send, &frame(20, 1, "x")).await.unwrap();
+        let Err(err) = recv_frame(&mut recv).await else { panic!("timed out call answered") };
+        assert!(
+            matches!(err, Nwd1QuicError::Read(ReadError::Reset(code)) if code == VarInt::from_u32(HANDLER_ERROR_CODE))
+        );
+        running.shutdown.cancel();
+    }
+}
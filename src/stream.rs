@@ -0,0 +1,166 @@
+//! [`futures`] `Stream` / `Sink` adapters over quinn streams.
+
+use std::future::Future;
+use std::pin::{Pin, pin};
+use std::task::{Context, Poll, ready};
+
+use bytes::Bytes;
+use futures::{Sink, Stream};
+use nwd1::Frame;
+use quinn::{RecvStream, SendStream, WriteError};
+
+use crate::{FrameReader, Nwd1QuicError, encode_header};
+
+/// A [`Stream`] of frames received from a [`RecvStream`].
+///
+/// Yields the same frames as repeated [`recv_frame`](crate::recv_frame) calls and ends when the
+/// peer finishes the stream.
+#[derive(Debug)]
+pub struct FrameStream {
+    reader: FrameReader,
+}
+
+impl FrameStream {
+    /// Wrap a receive stream.
+    pub fn new(stream: RecvStream) -> Self {
+        Self { reader: FrameReader::new(stream) }
+    }
+
+    /// Consume the adapter, returning the underlying reader.
+    pub fn into_inner(self) -> FrameReader {
+        self.reader
+    }
+}
+
+impl From<FrameReader> for FrameStream {
+    fn from(reader: FrameReader) -> Self {
+        Self { reader }
+    }
+}
+
+impl Stream for FrameStream {
+    type Item = Result<Frame, Nwd1QuicError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().reader.poll_next_frame(cx).map(Result::transpose)
+    }
+}
+
+/// A [`Sink`] writing frames to a [`SendStream`].
+///
+/// Each frame is written like [`send_frame`](crate::send_frame), header and payload as separate
+/// chunks so the payload is never copied, and fully written before the next one is accepted.
+/// Closing the sink finishes the stream.
+#[derive(Debug)]
+pub struct FrameSink {
+    stream: SendStream,
+    pending: [Bytes; 2],
+}
+
+impl FrameSink {
+    /// Wrap a send stream.
+    pub fn new(stream: SendStream) -> Self {
+        Self { stream, pending: Default::default() }
+    }
+
+    /// Consume the adapter, returning the underlying stream.
+    ///
+    /// Bytes of a frame that was started but not flushed are discarded.
+    pub fn into_inner(self) -> SendStream {
+        self.stream
+    }
+}
+
+impl Sink<Frame> for FrameSink {
+    type Error = Nwd1QuicError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Nwd1QuicError>> {
+        self.poll_flush(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, frame: Frame) -> Result<(), Nwd1QuicError> {
+        debug_assert!(
+            self.pending.iter().all(Bytes::is_empty),
+            "start_send called without poll_ready"
+        );
+        let header = Bytes::copy_from_slice(&encode_header(&frame));
+        self.get_mut().pending = [header, frame.payload];
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Nwd1QuicError>> {
+        let this = self.get_mut();
+        // `write_chunks` empties the chunks it wrote and writes nothing unless it completes
+        while this.pending.iter().any(|chunk| !chunk.is_empty()) {
+            ready!(pin!(this.stream.write_chunks(&mut this.pending)).poll(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Nwd1QuicError>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Poll::Ready(self.get_mut().stream.finish().map_err(|e| WriteError::from(e).into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{frame, loopback};
+    use futures::{SinkExt, StreamExt, TryStreamExt};
+
+    #[tokio::test]
+    async fn sink_to_stream() {
+        let lb = loopback().await;
+        let mut sink = FrameSink::new(lb.client.open_uni().await.unwrap());
+
+        let writer = tokio::spawn(async move {
+            let mut frames =
+                futures::stream::iter((0..16).map(|counter| {
+                    Ok(frame(counter, (counter % 2) as u8, vec![counter as u8; 4096]))
+                }));
+            sink.send_all(&mut frames).await.unwrap();
+            sink.close().await.unwrap();
+        });
+
+        let stream = FrameStream::new(lb.server.accept_uni().await.unwrap());
+        let odd: Vec<Frame> =
+            stream.try_filter(|f| futures::future::ready(f.kind == 1)).try_collect().await.unwrap();
+        writer.await.unwrap();
+
+        assert_eq!(odd.len(), 8);
+        for (f, counter) in odd.iter().zip((1..16).step_by(2)) {
+            assert_eq!(f.id.counter(), counter);
+            assert_eq!(f.payload, vec![counter as u8; 4096]);
+        }
+    }
+
+    #[tokio::test]
+    async fn forward_between_streams() {
+        let lb = loopback().await;
+        let server = lb.server.clone();
+
+        // Echo server: forward every received frame back on the same bi stream
+        let echo = tokio::spawn(async move {
+            let (send, recv) = server.accept_bi().await.unwrap();
+            FrameStream::new(recv).forward(FrameSink::new(send)).await.unwrap();
+        });
+
+        let (send, recv) = lb.client.open_bi().await.unwrap();
+        let mut sink = FrameSink::new(send);
+        for counter in 0..4 {
+            sink.send(frame(counter, (counter % 2) as u8, vec![counter as u8; 4096]))
+                .await
+                .unwrap();
+        }
+        sink.close().await.unwrap();
+
+        let counters: Vec<u64> =
+            FrameStream::new(recv).map_ok(|f| f.id.counter()).try_collect().await.unwrap();
+        assert_eq!(counters, vec![0, 1, 2, 3]);
+        echo.await.unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+//! Loopback quinn endpoints shared by the unit tests.
+
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use netid64::NetId64;
+use nwd1::Frame;
+use quinn::rustls::RootCertStore;
+use quinn::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer};
+use quinn::{ClientConfig, Connection, Endpoint, ServerConfig};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::{FrameHandler, InterceptorStack, Nwd1QuicError, Nwd1Server, ServerLimits};
+
+/// A frame with `ID` `1:7:counter`, `VER` 1 and the given `KIND` and payload.
+pub(crate) fn frame(counter: u64, kind: u8, payload: impl Into<Bytes>) -> Frame {
+    Frame { id: NetId64::make(1, 7, counter), kind, ver: 1, payload: payload.into() }
+}
+
+/// A connected client/server pair over `127.0.0.1`.
+///
+/// Holds both endpoints so the connections stay driven for the lifetime of the test.
+pub(crate) struct Loopback {
+    pub(crate) client: Connection,
+    pub(crate) server: Connection,
+    _endpoints: (Endpoint, Endpoint),
+}
+
+/// Self-signed server config for `localhost` plus a client config trusting it.
+pub(crate) fn configs() -> (ServerConfig, ClientConfig) {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+    let cert_der = CertificateDer::from(cert.cert);
+    let key = PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der());
+
+    let server = ServerConfig::with_single_cert(vec![cert_der.clone()], key.into()).unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert_der).unwrap();
+    let client = ClientConfig::with_root_certificates(Arc::new(roots)).unwrap();
+
+    (server, client)
+}
+
+pub(crate) fn localhost() -> SocketAddr {
+    (Ipv4Addr::LOCALHOST, 0).into()
+}
+
+pub(crate) async fn loopback() -> Loopback {
+    let (server_config, client_config) = configs();
+    let server_ep = Endpoint::server(server_config, localhost()).unwrap();
+    let mut client_ep = Endpoint::client(localhost()).unwrap();
+    client_ep.set_default_client_config(client_config);
+
+    let connecting = client_ep.connect(server_ep.local_addr().unwrap(), "localhost").unwrap();
+    let accepting = async { server_ep.accept().await.unwrap().await };
+    let (client, server) = tokio::join!(connecting, accepting);
+
+    Loopback {
+        client: client.unwrap(),
+        server: server.unwrap(),
+        _endpoints: (server_ep, client_ep),
+    }
+}
+
+/// An [`Nwd1Server`] on localhost with a client connected to it.
+pub(crate) struct RunningServer {
+    pub(crate) conn: Connection,
+    pub(crate) shutdown: CancellationToken,
+    pub(crate) serving: JoinHandle<Result<(), Nwd1QuicError>>,
+    _endpoint: Endpoint,
+}
+
+/// Serve `handler` on localhost and connect a client to it.
+pub(crate) async fn start_server(
+    limits: ServerLimits,
+    handler: impl FrameHandler,
+) -> RunningServer {
+    start_server_with(limits, InterceptorStack::new(), handler).await
+}
+
+/// Serve `handler` like [`start_server`], running frames through `interceptors`.
+pub(crate) async fn start_server_with(
+    limits: ServerLimits,
+    interceptors: InterceptorStack,
+    handler: impl FrameHandler,
+) -> RunningServer {
+    let (server_config, client_config) = configs();
+    let mut server = Nwd1Server::bind(localhost(), server_config).unwrap();
+    server.set_limits(limits);
+    server.set_interceptors(interceptors);
+    let addr = server.local_addr().unwrap();
+    let shutdown = server.shutdown_token();
+    let serving = tokio::spawn(server.serve(handler));
+
+    let mut endpoint = Endpoint::client(localhost()).unwrap();
+    endpoint.set_default_client_config(client_config);
+    let conn = endpoint.connect(addr, "localhost").unwrap().await.unwrap();
+    RunningServer { conn, shutdown, serving, _endpoint: endpoint }
+}
@@ -0,0 +1,342 @@
+//! Trace context propagation with W3C `traceparent` headers.
+//!
+//! A [`TraceContext`] in scope ([`with_trace_context`]) is attached as a [`TRACEPARENT`]
+//! header to frames sent through [`FrameSender`](crate::FrameSender),
+//! [`RpcClient`](crate::RpcClient) and [`CallSender`](crate::CallSender). An
+//! [`Nwd1Server`](crate::Nwd1Server) continues the trace: each handler call runs in a `handle`
+//! span and with a child context of the one its frame carried in scope. The header is removed
+//! before the frame reaches the handler, or is returned by
+//! [`FrameReceiver::recv`](crate::FrameReceiver::recv).
+
+use std::fmt;
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+
+use nwd1::Frame;
+use tracing::Span;
+use tracing::field::{Empty, display};
+
+use crate::interceptor::clone_frame;
+use crate::{HEADERS_FLAG, Nwd1QuicError, set_headers, take_headers};
+
+/// Header carrying the trace context, in the W3C Trace Context format.
+pub const TRACEPARENT: &str = "traceparent";
+
+/// Trace flag marking the trace as sampled by the caller.
+const SAMPLED: u8 = 0x01;
+
+tokio::task_local! {
+    static TRACE: TraceContext;
+}
+
+/// The position of a unit of work within a distributed trace.
+///
+/// Formats as a `traceparent` value: `00-{trace_id:032x}-{span_id:016x}-{flags:02x}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    /// The trace shared by every hop of a request; never zero.
+    pub trace_id: u128,
+    /// The current unit of work, the parent of the next hop's; never zero.
+    pub span_id: u64,
+    /// W3C trace flags; bit 0 marks the trace as sampled.
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Start a new, sampled trace.
+    pub fn new_root() -> Self {
+        let trace_id = (u128::from(random_id()) << 64) | u128::from(random_id());
+        Self { trace_id, span_id: random_id(), flags: SAMPLED }
+    }
+
+    /// The context of work done on behalf of this one: same trace, new span.
+    pub fn child(&self) -> Self {
+        Self { span_id: random_id(), ..*self }
+    }
+
+    /// Whether the caller sampled the trace.
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    /// Parse a `traceparent` value, rejecting malformed or all-zero ids.
+    ///
+    /// Versions after `00` are accepted if they start with the `00` fields.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut fields = traceparent.split('-');
+        let version = fields.next().filter(|v| is_hex(v, 2))?;
+        let trace_id = fields.next().filter(|v| is_hex(v, 32))?;
+        let span_id = fields.next().filter(|v| is_hex(v, 16))?;
+        let flags = fields.next().filter(|v| is_hex(v, 2))?;
+        if version == "ff" || (version == "00" && fields.next().is_some()) {
+            return None;
+        }
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+}
+
+/// The trace context of the current task, if one is in scope.
+pub fn current_trace_context() -> Option<TraceContext> {
+    TRACE.try_with(|context| *context).ok()
+}
+
+/// Run `fut` with `context` in scope.
+pub async fn with_trace_context<F: Future>(context: TraceContext, fut: F) -> F::Output {
+    TRACE.scope(context, fut).await
+}
+
+/// Run `fut` with `context` in scope, if there is one.
+pub(crate) async fn scoped<F: Future>(context: Option<TraceContext>, fut: F) -> F::Output {
+    match context {
+        Some(context) => with_trace_context(context, fut).await,
+        None => fut.await,
+    }
+}
+
+/// Remove the [`TRACEPARENT`] header from a received frame, returning the context it carried.
+///
+/// The envelope goes too once no other headers remain. Malformed headers are left in place;
+/// they are reported to whoever takes them.
+pub(crate) fn arrive(frame: &mut Frame) -> Option<TraceContext> {
+    if frame.ver & HEADERS_FLAG == 0 {
+        return None;
+    }
+    let mut stripped = clone_frame(frame);
+    let mut headers = take_headers(&mut stripped).ok()?;
+    let traceparent = headers.remove(TRACEPARENT)?;
+    set_headers(&mut stripped, &headers).ok()?;
+    *frame = stripped;
+    std::str::from_utf8(&traceparent).ok().and_then(TraceContext::parse)
+}
+
+/// `frame` carrying the trace context in scope as its [`TRACEPARENT`] header, replacing one it
+/// already has, or `None` if there is no context in scope.
+pub(crate) fn inject(frame: &Frame) -> Result<Option<Frame>, Nwd1QuicError> {
+    let Some(context) = current_trace_context() else { return Ok(None) };
+    let mut frame = clone_frame(frame);
+    let mut headers = take_headers(&mut frame)?;
+    headers.insert(TRACEPARENT, context.to_string());
+    set_headers(&mut frame, &headers)?;
+    Ok(Some(frame))
+}
+
+/// The span a handler call for `frame` runs in, linked to the caller's context `parent`.
+pub(crate) fn handle_span(frame: &Frame, parent: Option<&TraceContext>) -> Span {
+    let span = tracing::debug_span!(
+        "handle",
+        id = frame.id.raw(),
+        kind = frame.kind,
+        trace_id = Empty,
+        parent_id = Empty,
+    );
+    if let Some(parent) = parent {
+        span.record("trace_id", display(format_args!("{:032x}", parent.trace_id)));
+        span.record("parent_id", display(format_args!("{:016x}", parent.span_id)));
+    }
+    span
+}
+
+fn is_hex(field: &str, len: usize) -> bool {
+    field.len() == len && field.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// A non-zero id from the standard library's randomly keyed hasher.
+fn random_id() -> u64 {
+    RandomState::new().hash_one(()).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+    use std::sync::{Arc, Mutex};
+
+    use netid64::NetId64;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    use super::*;
+    use crate::test_util::{frame, loopback, start_server};
+    use crate::{
+        FrameReceiver, FrameSender, Headers, ReplySender, ServerLimits, recv_frame, send_frames,
+    };
+
+    /// Keeps every span's name and fields in memory.
+    #[derive(Clone, Default)]
+    struct Recorder {
+        spans: Arc<Mutex<Vec<(&'static str, String)>>>,
+    }
+
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            write!(self.0, " {}={value:?}", field.name()).unwrap();
+        }
+    }
+
+    impl Subscriber for Recorder {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut fields = String::new();
+            span.record(&mut Fields(&mut fields));
+            let mut spans = self.spans.lock().unwrap();
+            spans.push((span.metadata().name(), fields));
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].1));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, _: &Event<'_>) {}
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    impl Recorder {
+        fn has(&self, name: &str, fields: &[&str]) -> bool {
+            let spans = self.spans.lock().unwrap();
+            spans.iter().any(|(n, f)| *n == name && fields.iter().all(|field| f.contains(field)))
+        }
+    }
+
+    #[test]
+    fn parses_and_formats_traceparent() {
+        let value = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(value).unwrap();
+        assert_eq!(context.span_id, 0x00f0_67aa_0ba9_02b7);
+        assert!(context.is_sampled());
+        assert_eq!(context.to_string(), value);
+
+        let child = context.child();
+        assert_eq!(child.trace_id, context.trace_id);
+        assert_ne!(child.span_id, context.span_id);
+
+        assert!(
+            TraceContext::parse("01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x")
+                .is_some()
+        );
+        for bad in [
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-x",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902-01",
+        ] {
+            assert!(TraceContext::parse(bad).is_none(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn inject_replaces_traceparent() {
+        let mut frame = Frame { ver: 2, ..frame(1, 3, "x") };
+        assert!(inject(&frame).unwrap().is_none());
+
+        let stale = TraceContext::new_root();
+        let mut headers = Headers::new();
+        headers.insert(TRACEPARENT, stale.to_string());
+        set_headers(&mut frame, &headers).unwrap();
+
+        let current = TraceContext::new_root();
+        let mut injected =
+            with_trace_context(current, async { inject(&frame) }).await.unwrap().unwrap();
+        assert_eq!(arrive(&mut injected), Some(current));
+        assert_eq!((injected.ver, &injected.payload[..]), (2, &b"x"[..]));
+    }
+
+    #[tokio::test]
+    async fn server_continues_trace() {
+        let recorder = Recorder::default();
+        let _default = tracing::subscriber::set_default(recorder.clone());
+
+        // Answers with the frame's payload and the trace context the handler runs in
+        let handler = |frame: Frame, reply: ReplySender| async move {
+            let context = current_trace_context().map(|c| c.to_string()).unwrap_or_default();
+            let payload = [&frame.payload[..], b" ", context.as_bytes()].concat();
+            reply.send(&Frame { payload: payload.into(), ..frame }).await
+        };
+        let running = start_server(ServerLimits::default(), handler).await;
+
+        let (send, mut recv) = running.conn.open_bi().await.unwrap();
+        let mut tx = FrameSender::new(send);
+        let request = frame(1, 3, "req");
+        let root = TraceContext::new_root();
+        with_trace_context(root, tx.send(&request)).await.unwrap();
+
+        // The handler sees the frame as it was sent, without the header
+        let response = recv_frame(&mut recv).await.unwrap().unwrap();
+        assert_eq!((response.ver, &response.payload[..4]), (1, &b"req "[..]));
+        let remote = std::str::from_utf8(&response.payload[4..]).unwrap();
+        let remote = TraceContext::parse(remote).unwrap();
+        assert_eq!(remote.trace_id, root.trace_id);
+        assert_ne!(remote.span_id, root.span_id);
+
+        let ver = format!("ver={}", 1 | HEADERS_FLAG);
+        assert!(recorder.has("send_frame", &["kind=3", &ver]));
+        let trace_id = format!("trace_id={:032x}", root.trace_id);
+        let parent_id = format!("parent_id={:016x}", root.span_id);
+        assert!(recorder.has("handle", &["kind=3", &trace_id, &parent_id]));
+        assert!(recorder.has("recv_frame", &["kind=3", "ver=1", "len=59"]));
+    }
+
+    #[tokio::test]
+    async fn receiver_strips_traceparent() {
+        let pair = loopback().await;
+        let (send, _) = pair.client.open_bi().await.unwrap();
+        let mut tx = FrameSender::new(send);
+        let frame = Frame { ver: 2, ..frame(1, 3, "x") };
+        let mut headers = Headers::new();
+        headers.insert("tenant", "a");
+        let root = TraceContext::new_root();
+        with_trace_context(root, async {
+            tx.send(&frame).await.unwrap();
+            tx.send_with_headers(&frame, &headers).await.unwrap();
+        })
+        .await;
+
+        let (_, recv) = pair.server.accept_bi().await.unwrap();
+        let mut rx = FrameReceiver::new(recv);
+        let received = rx.recv().await.unwrap().unwrap();
+        assert_eq!((received.ver, &received.payload[..]), (2, &b"x"[..]));
+        assert_eq!(rx.trace_context(), Some(root));
+        let (received, rest) = rx.recv_with_headers().await.unwrap().unwrap();
+        assert_eq!((received.ver, &received.payload[..], rest), (2, &b"x"[..], headers));
+    }
+
+    #[tokio::test]
+    async fn reader_and_batches_have_spans() {
+        let recorder = Recorder::default();
+        let _default = tracing::subscriber::set_default(recorder.clone());
+
+        let pair = loopback().await;
+        let (mut send, _) = pair.client.open_bi().await.unwrap();
+        let frames: Vec<_> = (0..3).map(|i| Frame { ver: 2, ..frame(i, 3, vec![0; 10]) }).collect();
+        send_frames(&mut send, &frames).await.unwrap();
+        send.finish().unwrap();
+
+        let (_, recv) = pair.server.accept_bi().await.unwrap();
+        let mut rx = FrameReceiver::new(recv);
+        while rx.recv().await.unwrap().is_some() {}
+
+        assert!(recorder.has("send_frames", &["frames=3", "len=105"]));
+        let id = format!("id={}", NetId64::make(1, 7, 2).raw());
+        assert!(recorder.has("next_frame", &[&id, "kind=3", "ver=2", "len=10"]));
+    }
+}
@@ -0,0 +1,167 @@
+//! One frame per unidirectional stream.
+//!
+//! Every frame gets a stream of its own, so a large or slow frame never delays the ones sent
+//! after it, at the cost of opening a stream per frame.
+
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use nwd1::Frame;
+use quinn::{Connection, ConnectionError, RecvStream, WriteError};
+use tokio::sync::mpsc;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::{FrameLimits, Nwd1QuicError, recv_frame_with_limits, send_frame};
+
+/// Frames buffered by a [`UniAcceptor`] before its streams wait for the application.
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Send `frame` on a new unidirectional stream and finish it.
+///
+/// Returns once the frame is handed to quinn; it is delivered independently of frames sent on
+/// other streams.
+pub async fn send_frame_uni(conn: &Connection, frame: &Frame) -> Result<(), Nwd1QuicError> {
+    let mut stream = conn.open_uni().await?;
+    send_frame(&mut stream, frame).await?;
+    stream.finish().map_err(WriteError::from)?;
+    Ok(())
+}
+
+/// Accepts unidirectional streams carrying one frame each, yielding frames as they complete.
+///
+/// Streams are read concurrently, so frames come out in completion order rather than the
+/// order they were sent. A stream that fails or holds a malformed frame yields an error and
+/// does not affect the others. The [`Stream`] ends when the connection is closed; other
+/// connection errors are yielded once before it ends.
+///
+/// Accepting stops when the acceptor is dropped.
+#[derive(Debug)]
+pub struct UniAcceptor {
+    frames: mpsc::Receiver<Result<Frame, Nwd1QuicError>>,
+    task: JoinHandle<()>,
+}
+
+impl UniAcceptor {
+    /// Start accepting streams on `conn`.
+    pub fn new(conn: Connection) -> Self {
+        Self::with_limits(conn, FrameLimits::default())
+    }
+
+    /// Start accepting streams on `conn`, enforcing `limits` on every frame.
+    pub fn with_limits(conn: Connection, limits: FrameLimits) -> Self {
+        let (tx, frames) = mpsc::channel(ACCEPT_BACKLOG);
+        let task = tokio::spawn(accept_loop(conn, limits, tx));
+        Self { frames, task }
+    }
+
+    /// Receive the next frame, or `None` once the connection is closed.
+    pub async fn accept(&mut self) -> Option<Result<Frame, Nwd1QuicError>> {
+        self.frames.recv().await
+    }
+}
+
+impl Stream for UniAcceptor {
+    type Item = Result<Frame, Nwd1QuicError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().frames.poll_recv(cx)
+    }
+}
+
+impl Drop for UniAcceptor {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn accept_loop(
+    conn: Connection,
+    limits: FrameLimits,
+    tx: mpsc::Sender<Result<Frame, Nwd1QuicError>>,
+) {
+    // Dropping the set when the loop ends aborts streams still being read
+    let mut streams = JoinSet::new();
+    loop {
+        tokio::select! {
+            accepted = conn.accept_uni() => match accepted {
+                Ok(stream) => {
+                    streams.spawn(read_one(stream, limits.clone(), tx.clone()));
+                }
+                Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => {
+                    return;
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e.into())).await;
+                    return;
+                }
+            },
+            // Reap finished readers so the set does not grow with every stream
+            Some(_) = streams.join_next() => {}
+            () = tx.closed() => return,
+        }
+    }
+}
+
+async fn read_one(
+    mut stream: RecvStream,
+    limits: FrameLimits,
+    tx: mpsc::Sender<Result<Frame, Nwd1QuicError>>,
+) {
+    let result = match recv_frame_with_limits(&mut stream, &limits).await {
+        Ok(Some(frame)) => Ok(frame),
+        // An empty stream carries nothing to report
+        Ok(None) => return,
+        Err(e) => Err(e),
+    };
+    let _ = tx.send(result).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::test_util::{frame, loopback};
+
+    #[tokio::test]
+    async fn yields_frames_from_each_stream() {
+        let lb = loopback().await;
+        let mut acceptor = UniAcceptor::new(lb.server.clone());
+
+        send_frame_uni(&lb.client, &frame(0, 1, vec![0; 2 * 1024 * 1024])).await.unwrap();
+        for counter in 1..4 {
+            send_frame_uni(&lb.client, &frame(counter, 1, vec![counter as u8; 16])).await.unwrap();
+        }
+
+        let mut counters = Vec::new();
+        for _ in 0..4 {
+            let frame = acceptor.accept().await.unwrap().unwrap();
+            assert_eq!(
+                frame.payload.len(),
+                if frame.id.counter() == 0 { 2 * 1024 * 1024 } else { 16 }
+            );
+            counters.push(frame.id.counter());
+        }
+        counters.sort();
+        assert_eq!(counters, [0, 1, 2, 3]);
+
+        lb.client.close(0u32.into(), b"done");
+        assert!(acceptor.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn isolates_failing_streams() {
+        let lb = loopback().await;
+        let mut acceptor = UniAcceptor::new(lb.server.clone());
+
+        let mut bad = lb.client.open_uni().await.unwrap();
+        bad.write_all(b"HTTP/1.1 200 OK\r\n").await.unwrap();
+        bad.finish().unwrap();
+        let Some(Err(err)) = acceptor.accept().await else { panic!("bad stream accepted") };
+        assert!(matches!(err, Nwd1QuicError::BadMagic(_)));
+
+        send_frame_uni(&lb.client, &frame(5, 1, vec![5; 32])).await.unwrap();
+        assert_eq!(acceptor.accept().await.unwrap().unwrap().id.counter(), 5);
+    }
+}